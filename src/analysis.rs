@@ -0,0 +1,317 @@
+use super::{GCode, SelectIds, Settings};
+use crate::print_analyzer::collision::Collision;
+use crate::print_analyzer::contour::{Orientation, Path, Polygon};
+use crate::print_analyzer::flow::{Bead, FlowCheck, Violation};
+use crate::print_analyzer::lint::{BuildVolume, Diagnostic, Linter, Severity};
+use crate::print_analyzer::machine::OutOfBounds;
+use crate::print_analyzer::overhang::{Overhang, Support, ANGLES};
+use crate::print_analyzer::planner::{format_duration, TimeEstimate};
+use crate::print_analyzer::seam::Seam;
+use crate::print_analyzer::travel::TravelSavings;
+use crate::print_analyzer::usage::{Usage, UsageReport};
+use crate::print_analyzer::Id;
+use bevy::prelude::*;
+use bevy_egui::EguiContexts;
+use std::collections::HashMap;
+
+// analysis results, recomputed whenever the gcode is re-rendered
+#[derive(Default, Resource)]
+pub struct Reports {
+    pub time: TimeEstimate,
+    pub usage: UsageReport,
+    pub beads: HashMap<Id, Bead>,
+    pub flow: FlowCheck,
+    pub bounds: Vec<(Id, OutOfBounds)>,
+    pub collisions: Vec<Collision>,
+    pub overhangs: HashMap<Id, Overhang>,
+    pub paths: Vec<Path>,
+    // closed paths of every layer, outlines and holes
+    pub polygons: Vec<Vec<Polygon>>,
+    pub seams: Vec<Seam>,
+    // mm of moves that don't extrude
+    pub travel: f32,
+    // what the last travel optimization saved, with the seconds it took off the estimate
+    pub travel_savings: Option<(TravelSavings, f32)>,
+    pub linter: Linter,
+    pub diagnostics: Vec<Diagnostic>,
+    // totals of the file as it was loaded, to show how edits change them
+    initial_time: Option<f32>,
+    initial_usage: Option<Usage>,
+}
+
+pub fn update_reports(mut reports: ResMut<Reports>, gcode: Res<GCode>, settings: Res<Settings>) {
+    let reports = reports.as_mut();
+    let machine = &settings.machine;
+    let time = gcode.0.estimate_time(&machine.limits);
+    reports.initial_time.get_or_insert(time.total);
+    reports.time = time;
+    let usage = gcode.0.filament_usage(&settings.filament);
+    reports.initial_usage.get_or_insert(usage.total);
+    reports.usage = usage;
+    reports.beads = gcode.0.beads(&settings.filament);
+    reports.flow = gcode.0.check_flow(
+        &reports.beads,
+        settings.max_volumetric_flow,
+        &machine.limits,
+    );
+    reports.bounds = gcode.0.check_bounds(machine);
+    reports.collisions = gcode
+        .0
+        .travel_collisions(&reports.beads, settings.travel_clearance);
+    reports.overhangs = gcode.0.overhangs(&reports.beads, &ANGLES);
+    reports.paths = gcode.0.paths();
+    reports.polygons = gcode.0.polygons(&reports.paths, &reports.beads);
+    reports.seams = gcode.0.seams(&reports.paths);
+    reports.travel = gcode.0.travel_distance();
+    reports.linter.replace(Box::new(BuildVolume {
+        profile: machine.clone(),
+    }));
+    reports.diagnostics = reports.linter.run(&gcode.0);
+}
+
+// returns true if clicked
+fn violation_label(ui: &mut egui::Ui, id: &Id, violation: &Violation) -> bool {
+    let text = match violation {
+        Violation::Flow { flow, max } => format!("{:?}: {:.1} of {:.1}mm3/s", id, flow, max),
+        Violation::Feedrate { axis, speed, max } => {
+            format!("{:?}: {} {:.0} of {:.0}mm/s", id, axis, speed, max)
+        }
+    };
+    ui.link(text).clicked()
+}
+
+// returns true if clicked
+fn diagnostic_label(ui: &mut egui::Ui, diagnostic: &Diagnostic) -> bool {
+    let color = match diagnostic.severity {
+        Severity::Error => egui::Color32::RED,
+        Severity::Warning => egui::Color32::YELLOW,
+        Severity::Info => egui::Color32::LIGHT_BLUE,
+    };
+    let text = egui::RichText::new(format!("{}: {}", diagnostic.rule, diagnostic.message));
+    ui.link(text.color(color)).clicked()
+}
+
+fn usage_label(ui: &mut egui::Ui, name: &str, usage: &Usage) {
+    ui.label(format!(
+        "{}: {:.2}m, {:.2}cm3, {:.2}g, {:.2}",
+        name,
+        usage.length / 1000.0,
+        usage.volume / 1000.0,
+        usage.mass,
+        usage.cost
+    ));
+}
+
+pub fn report_panel(
+    mut commands: Commands,
+    mut contexts: EguiContexts,
+    mut reports: ResMut<Reports>,
+    gcode: Res<GCode>,
+) {
+    let reports = reports.as_mut();
+    egui::SidePanel::new(egui::panel::Side::Right, "reports")
+        .resizable(true)
+        .show(contexts.ctx_mut(), |ui| {
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                ui.heading("print time");
+                let total = reports.time.total;
+                ui.label(format!("estimate: {}", format_duration(total)));
+                if let Some(initial) = reports.initial_time {
+                    let delta = total - initial;
+                    let sign = if delta < 0.0 { "-" } else { "+" };
+                    ui.label(format!("change: {}{}", sign, format_duration(delta.abs())));
+                }
+                ui.collapsing("per layer", |ui| {
+                    for (z, t) in &reports.time.layers {
+                        ui.label(format!("z {:.2}: {}", z, format_duration(*t)));
+                    }
+                });
+                ui.separator();
+                ui.heading("filament");
+                let usage = &reports.usage;
+                usage_label(ui, "total", &usage.total);
+                if let Some(initial) = reports.initial_usage {
+                    ui.label(format!(
+                        "change: {:+.2}g, {:+.2}",
+                        usage.total.mass - initial.mass,
+                        usage.total.cost - initial.cost
+                    ));
+                }
+                ui.collapsing("per tool", |ui| {
+                    for (tool, u) in &usage.tools {
+                        usage_label(ui, &format!("T{}", tool), u);
+                    }
+                });
+                ui.collapsing("per move type", |ui| {
+                    for (label, u) in &usage.labels {
+                        usage_label(ui, &format!("{:?}", label), u);
+                    }
+                });
+                ui.collapsing("per layer", |ui| {
+                    for (z, u) in &usage.layers {
+                        usage_label(ui, &format!("z {:.2}", z), u);
+                    }
+                });
+                ui.collapsing("per shape", |ui| {
+                    for shape in &gcode.0.shapes {
+                        if let Some(u) = usage.shapes.get(&shape.id) {
+                            usage_label(ui, &format!("{:?}", shape.id), u);
+                        }
+                    }
+                });
+                ui.separator();
+                ui.heading("flow limits");
+                let violations = &reports.flow.violations;
+                ui.label(format!("{} moves over the limit", violations.len()));
+                ui.collapsing("violations", |ui| {
+                    for (id, violation) in violations {
+                        if violation_label(ui, id, violation) {
+                            commands.insert_resource(SelectIds(vec![*id]));
+                        }
+                    }
+                });
+                ui.separator();
+                ui.heading("build volume");
+                ui.label(format!("{} moves out of bounds", reports.bounds.len()));
+                ui.collapsing("out of bounds", |ui| {
+                    for (id, kind) in &reports.bounds {
+                        let text = match kind {
+                            OutOfBounds::OffBed => format!("{:?}: off the bed", id),
+                            OutOfBounds::BelowBed => format!("{:?}: below the bed", id),
+                            OutOfBounds::AboveMaxZ => format!("{:?}: above max z", id),
+                            OutOfBounds::InZone(zone) => format!("{:?}: inside {}", id, zone),
+                        };
+                        if ui.link(text).clicked() {
+                            commands.insert_resource(SelectIds(vec![*id]));
+                        }
+                    }
+                });
+                ui.separator();
+                ui.heading("travel collisions");
+                let total: f32 = reports.collisions.iter().map(|c| c.length).sum();
+                ui.label(format!(
+                    "{} travels cross printed parts for {:.1}mm",
+                    reports.collisions.len(),
+                    total
+                ));
+                ui.collapsing("crossings", |ui| {
+                    for c in &reports.collisions {
+                        let text = format!("{:?}: {:.2}mm at z {:.2}", c.id, c.length, c.layer);
+                        if ui.link(text).clicked() {
+                            commands.insert_resource(SelectIds(vec![c.id]));
+                        }
+                    }
+                });
+                ui.separator();
+                ui.heading("overhangs");
+                // select every bead at or past a class to slow it down or cool it
+                let mut classes = vec![("bridges".to_string(), Support::Bridge)];
+                for (bucket, angle) in ANGLES.iter().enumerate().rev() {
+                    classes.push((format!("over {}°", angle), Support::Overhang(bucket)));
+                }
+                for (name, class) in classes {
+                    let ids: Vec<Id> = reports
+                        .overhangs
+                        .iter()
+                        .filter(|(_, o)| match (o.class, class) {
+                            (Support::Overhang(a), Support::Overhang(b)) => a >= b,
+                            (a, b) => a == b,
+                        })
+                        .map(|(id, _)| *id)
+                        .collect();
+                    ui.horizontal(|ui| {
+                        ui.label(format!("{}: {}", name, ids.len()));
+                        if ui.button("select").clicked() && !ids.is_empty() {
+                            commands.insert_resource(SelectIds(ids));
+                        }
+                    });
+                }
+                ui.collapsing("steepest", |ui| {
+                    let mut worst: Vec<(&Id, &Overhang)> = reports
+                        .overhangs
+                        .iter()
+                        .filter(|(_, o)| o.class != Support::Supported)
+                        .collect();
+                    worst.sort_by(|a, b| b.1.angle.total_cmp(&a.1.angle));
+                    for (id, o) in worst.into_iter().take(50) {
+                        let text = format!(
+                            "{:?}: {:.0}°, {:.0}% supported",
+                            id,
+                            o.angle,
+                            o.supported * 100.0
+                        );
+                        if ui.link(text).clicked() {
+                            commands.insert_resource(SelectIds(vec![*id]));
+                        }
+                    }
+                });
+                ui.separator();
+                ui.heading("travel");
+                ui.label(format!("{:.1}mm of travel", reports.travel));
+                if let Some((savings, seconds)) = reports.travel_savings {
+                    ui.label(format!(
+                        "last reorder: {:.1}mm shorter, {} faster",
+                        savings.before - savings.after,
+                        format_duration(seconds)
+                    ));
+                }
+                ui.separator();
+                ui.heading("seams");
+                let loops = reports.paths.iter().filter(|p| p.closed).count();
+                ui.label(format!(
+                    "{} loops, {} open paths",
+                    loops,
+                    reports.paths.len() - loops
+                ));
+                // every wall of every hole, e.g. to scale them for fit
+                let holes: Vec<&Polygon> = reports
+                    .polygons
+                    .iter()
+                    .flatten()
+                    .filter(|p| p.orientation == Orientation::Hole)
+                    .collect();
+                ui.horizontal(|ui| {
+                    ui.label(format!("hole walls: {}", holes.len()));
+                    if ui.button("select").clicked() && !holes.is_empty() {
+                        let ids = holes
+                            .iter()
+                            .flat_map(|p| reports.paths[p.path].vertices.iter().copied())
+                            .collect();
+                        commands.insert_resource(SelectIds(ids));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    ui.label(format!("seams: {}", reports.seams.len()));
+                    if ui.button("select").clicked() && !reports.seams.is_empty() {
+                        let ids = reports.seams.iter().map(|s| s.id).collect();
+                        commands.insert_resource(SelectIds(ids));
+                    }
+                });
+                ui.separator();
+                ui.heading("lint");
+                let mut changed = false;
+                ui.collapsing("rules", |ui| {
+                    for (rule, enabled) in reports.linter.rules.iter_mut() {
+                        changed |= ui.checkbox(enabled, rule.name()).changed();
+                        for (name, value) in rule.params() {
+                            ui.horizontal(|ui| {
+                                ui.label(name);
+                                let drag = egui::DragValue::new(value)
+                                    .speed(0.01)
+                                    .clamp_range(0.0..=f32::MAX);
+                                changed |= ui.add(drag).changed();
+                            });
+                        }
+                    }
+                });
+                if changed {
+                    reports.diagnostics = reports.linter.run(&gcode.0);
+                }
+                for diagnostic in &reports.diagnostics {
+                    if diagnostic_label(ui, diagnostic) {
+                        commands.insert_resource(SelectIds(diagnostic.ids.clone()));
+                    }
+                }
+            })
+        });
+}
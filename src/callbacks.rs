@@ -1,4 +1,10 @@
 use super::*;
+use crate::print_analyzer::bed_mesh::read_bed_mesh;
+use crate::print_analyzer::seam::SeamStrategy;
+use crate::print_analyzer::warp::{HeightField, HeightMap};
+use bevy::render::render_asset::RenderAssetUsages;
+use bevy::render::render_resource::TextureFormat;
+use bevy::render::texture::{CompressedImageFormats, ImageSampler, ImageType};
 use std::collections::HashSet;
 
 #[derive(Default, Resource)]
@@ -10,6 +16,36 @@ pub struct HoleDelete;
 #[derive(Default, Resource)]
 pub struct SubdivideSelection(pub u32);
 
+// move the seams of the selected loops, nearest and aligned aim at the point
+#[derive(Resource)]
+pub struct RelocateSeams(pub SeamChoice, pub Vec2);
+
+#[derive(Default, Resource)]
+pub struct OptimizeTravel;
+
+// displace the selection in z, settings as picked in the ui
+#[derive(Clone, Resource)]
+pub struct WarpSelection {
+    pub choice: WarpChoice,
+    pub amplitude: f32,
+    pub size: f32,
+    pub max_len: f32,
+    pub path: String,
+}
+
+// bake a probed bed mesh into the first `layers` layers, fading out over `fade` mm
+#[derive(Clone, Resource)]
+pub struct BakeBedMesh {
+    pub path: String,
+    pub layers: usize,
+    pub fade: f32,
+    pub max_len: f32,
+}
+
+// replace the current selection, e.g. with the vertices a diagnostic points at
+#[derive(Default, Resource)]
+pub struct SelectIds(pub Vec<Id>);
+
 fn get_selections(mut s_query: Query<(&PickSelection, &Tag)>) -> HashSet<Id> {
     s_query
         .iter_mut()
@@ -50,3 +86,168 @@ pub fn subdivide_selection(
     commands.init_resource::<ForceRefresh>();
     commands.remove_resource::<SubdivideSelection>();
 }
+
+pub fn relocate_seams(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    s_query: Query<(&PickSelection, &Tag)>,
+    relocate: Res<RelocateSeams>,
+    bounds: Res<PrintBounds>,
+) {
+    let selection = get_selections(s_query);
+    let RelocateSeams(choice, point) = *relocate;
+    let center = ((bounds.min + bounds.max) / 2.0).truncate();
+    let strategy = match choice {
+        SeamChoice::Nearest => SeamStrategy::Nearest(point),
+        SeamChoice::Aligned => SeamStrategy::Aligned(center, point),
+        SeamChoice::Rear => SeamStrategy::Rear,
+        SeamChoice::Random => {
+            let seed = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64);
+            SeamStrategy::Random(seed)
+        }
+    };
+    gcode.0.relocate_seams(&selection, strategy);
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<RelocateSeams>();
+}
+
+pub fn optimize_travel(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    mut reports: ResMut<Reports>,
+    settings: Res<Settings>,
+) {
+    let limits = &settings.machine.limits;
+    let before = gcode.0.estimate_time(limits).total;
+    let savings = gcode.0.optimize_travel();
+    let after = gcode.0.estimate_time(limits).total;
+    reports.travel_savings = Some((savings, before - after));
+    commands.init_resource::<ForceRefresh>();
+    commands.remove_resource::<OptimizeTravel>();
+}
+
+// csv heights are in mm, png brightness goes from 0 to `amplitude`
+fn load_height_map(
+    path: &str,
+    min: Vec2,
+    max: Vec2,
+    amplitude: f32,
+) -> Result<HeightMap, Box<dyn std::error::Error>> {
+    if !path.to_lowercase().ends_with(".png") {
+        return HeightMap::from_csv(&std::fs::read_to_string(path)?, min, max);
+    }
+    let image = Image::from_buffer(
+        &std::fs::read(path)?,
+        ImageType::Extension("png"),
+        CompressedImageFormats::NONE,
+        false,
+        ImageSampler::Default,
+        RenderAssetUsages::default(),
+    )?;
+    let size = image.texture_descriptor.size;
+    let (cols, rows) = (size.width as usize, size.height as usize);
+    let bpp = image.data.len() / (cols * rows).max(1);
+    let wide = matches!(
+        image.texture_descriptor.format,
+        TextureFormat::R16Uint | TextureFormat::Rg16Uint | TextureFormat::Rgba16Unorm
+    );
+    let mut values = Vec::with_capacity(cols * rows);
+    // images start at the top row, height maps at min y
+    for row in image.data.chunks(cols * bpp).rev() {
+        for pixel in row.chunks(bpp) {
+            let level = if wide {
+                u16::from_le_bytes([pixel[0], pixel[1]]) as f32 / u16::MAX as f32
+            } else {
+                pixel[0] as f32 / u8::MAX as f32
+            };
+            values.push(level * amplitude);
+        }
+    }
+    HeightMap::new(cols, rows, values, min, max)
+}
+
+pub fn warp_selection(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    s_query: Query<(&PickSelection, &Tag)>,
+    warp: Res<WarpSelection>,
+    bounds: Res<PrintBounds>,
+) {
+    commands.remove_resource::<WarpSelection>();
+    let selection = get_selections(s_query);
+    if selection.is_empty() {
+        return;
+    }
+    // the shapes divide by their size
+    if warp.choice != WarpChoice::Map && warp.size <= 0.0 {
+        println!("warp size has to be above 0");
+        return;
+    }
+    let (min, max) = (bounds.min.truncate(), bounds.max.truncate());
+    let field = match warp.choice {
+        WarpChoice::Sine => HeightField::Sine {
+            amplitude: warp.amplitude,
+            wavelength: warp.size,
+            direction: Vec2::X,
+        },
+        WarpChoice::Dome => HeightField::Dome {
+            center: gcode.0.get_centroid(&selection).truncate(),
+            radius: warp.size,
+            height: warp.amplitude,
+        },
+        WarpChoice::Ramp => HeightField::Ramp {
+            origin: min,
+            slope: Vec2::X * warp.amplitude / warp.size,
+        },
+        WarpChoice::Map => match load_height_map(&warp.path, min, max, warp.amplitude) {
+            Ok(map) => HeightField::Map(map),
+            Err(e) => {
+                println!("failed to load height map: {}", e);
+                return;
+            }
+        },
+    };
+    gcode
+        .0
+        .displace_z(&selection, &field, warp.max_len.max(0.05));
+    commands.init_resource::<ForceRefresh>();
+}
+
+pub fn bake_bed_mesh(
+    mut commands: Commands,
+    mut gcode: ResMut<GCode>,
+    bake: Res<BakeBedMesh>,
+    settings: Res<Settings>,
+) {
+    commands.remove_resource::<BakeBedMesh>();
+    // meshes that don't say where they were probed cover the whole bed
+    let (min, max) = settings.machine.bed.bounds();
+    let mesh = std::fs::read_to_string(&bake.path)
+        .map_err(|e| e.into())
+        .and_then(|text| read_bed_mesh(&text, Vec2::from(min), Vec2::from(max)));
+    let mesh = match mesh {
+        Ok(mesh) => mesh,
+        Err(e) => {
+            println!("failed to load bed mesh: {}", e);
+            return;
+        }
+    };
+    gcode
+        .0
+        .bake_bed_mesh(&mesh, bake.layers, bake.fade, bake.max_len.max(0.05));
+    commands.init_resource::<ForceRefresh>();
+}
+
+pub fn select_ids(
+    mut commands: Commands,
+    mut s_query: Query<(&mut PickSelection, &Tag)>,
+    ids: Res<SelectIds>,
+) {
+    let ids: HashSet<Id> = ids.0.iter().copied().collect();
+    for (mut selection, tag) in s_query.iter_mut() {
+        selection.is_selected = ids.contains(&tag.id);
+    }
+    commands.remove_resource::<SelectIds>();
+}
@@ -1,14 +1,14 @@
 use crate::ForceRefresh;
 
 use super::{
-    print_analyzer::{Instruction, Vertex},
+    print_analyzer::{store::Slots, Instruction, Vertex},
     GCode, Id, Resource, Tag,
 };
 use bevy::prelude::*;
 use bevy_mod_picking::selection::PickSelection;
 use std::collections::{HashMap, HashSet};
 
-fn vec_diff<T>(curr: &Vec<T>, next: &Vec<T>) -> (bool, HashSet<(usize, T)>)
+fn vec_diff<T>(curr: &[T], next: &[T]) -> (bool, HashSet<(usize, T)>)
 where
     T: Copy + Eq + std::hash::Hash,
 {
@@ -49,9 +49,8 @@ where
     }
 }
 
-fn map_diff<S, T>(curr: &HashMap<S, T>, next: &HashMap<S, T>) -> (bool, HashMap<S, T>)
+fn map_diff<T>(curr: &Slots<T>, next: &Slots<T>) -> (bool, HashMap<Id, T>)
 where
-    S: Copy + PartialEq + Eq + core::hash::Hash,
     T: Clone,
 {
     let add = curr.len() < next.len();
@@ -66,8 +65,8 @@ where
             next_keys.difference(&curr_keys)
         }
     }
-    .collect::<HashSet<&S>>();
-    let mut diff: HashMap<S, T> = HashMap::new();
+    .collect::<HashSet<&Id>>();
+    let mut diff: HashMap<Id, T> = HashMap::new();
     if add {
         for key in diff_keys.iter() {
             let value = next.get(*key).unwrap();
@@ -173,15 +172,14 @@ pub struct GCodeDiff {
 impl GCodeDiff {
     fn apply(&self, gcode: &mut GCode) {
         if self.add {
-            for (i, id) in self.line_diff.iter() {
-                gcode.0.lines.insert(*i as usize, *id);
-            }
-            gcode.0.vertices.extend(self.vertex_diff.clone());
-            gcode.0.instructions.extend(self.instruction_diff.clone())
+            gcode.0.insert_lines_at(
+                self.line_diff.iter().copied().collect(),
+                self.vertex_diff.clone(),
+                self.instruction_diff.clone(),
+            );
         } else {
-            for (i, _id) in self.line_diff.iter() {
-                gcode.0.lines.remove(*i);
-            }
+            let ids = self.line_diff.iter().map(|(_, id)| *id).collect();
+            gcode.0.remove_lines(&ids);
         }
         gcode.0.assign_shapes();
     }
@@ -1,12 +1,15 @@
+mod analysis;
 mod callbacks;
 mod diff;
 mod pan_orbit;
 mod print_analyzer;
+mod reference;
 mod render;
 mod select;
 mod settings;
 mod ui;
 
+use analysis::*;
 use bevy::prelude::*;
 use bevy_egui::EguiPlugin;
 use bevy_mod_picking::prelude::*;
@@ -15,6 +18,7 @@ use diff::{undo_redo_selections, update_selection_log, SelectionLog, SetSelectio
 use pan_orbit::{pan_orbit_camera, PanOrbitCamera};
 use picking_core::PickingPluginsSettings;
 use print_analyzer::{Id, Parsed};
+use reference::*;
 use render::*;
 use select::*;
 use selection::send_selection_events;
@@ -53,8 +57,10 @@ fn setup(mut commands: Commands, mut filepath: ResMut<FilePath>) {
         }
     };
     filepath.0 = filename.to_string();
-    let gcode = print_analyzer::read(filename, false)
+    let mut gcode = print_analyzer::read(filename, false)
         .unwrap_or(print_analyzer::read(crate::settings::DEFAULT_GCODE, true).unwrap());
+    let settings = read_settings();
+    gcode.set_machine(&settings.machine);
     commands.insert_resource(AmbientLight {
         color: Color::WHITE,
         brightness: 255.0,
@@ -68,7 +74,11 @@ fn setup(mut commands: Commands, mut filepath: ResMut<FilePath>) {
             ..Default::default()
         },
     ));
-    commands.insert_resource(read_settings());
+    let mut reports = Reports::default();
+    for (rule, param, value) in &settings.lint {
+        reports.linter.set_param(rule, param, *value);
+    }
+    commands.insert_resource(settings);
     commands.insert_resource(VertexCounter::build(&gcode));
     commands.insert_resource(GCode(gcode));
     commands.init_resource::<ForceRefresh>();
@@ -76,6 +86,7 @@ fn setup(mut commands: Commands, mut filepath: ResMut<FilePath>) {
     commands.init_resource::<IdMap>();
     commands.init_resource::<EnablePanOrbit>();
     commands.init_resource::<SelectionLog>();
+    commands.insert_resource(reports);
 }
 fn main() {
     App::new()
@@ -110,19 +121,39 @@ fn main() {
                 toolbar,
                 right_click_menu.run_if(resource_exists::<RightClick>),
                 ui_system,
+                report_panel,
                 export_dialogue.run_if(resource_exists::<ExportDialogue>),
+                select_ids.run_if(resource_exists::<SelectIds>),
                 update_selections,
                 update_visibilities,
                 merge_delete.run_if(resource_exists::<MergeDelete>),
                 hole_delete.run_if(resource_exists::<HoleDelete>),
                 subdivide_selection.run_if(resource_exists::<SubdivideSelection>),
+                relocate_seams.run_if(resource_exists::<RelocateSeams>),
+                optimize_travel.run_if(resource_exists::<OptimizeTravel>),
+                warp_selection.run_if(resource_exists::<WarpSelection>),
+                bake_bed_mesh.run_if(resource_exists::<BakeBedMesh>),
             )
                 .chain(),
         )
+        .add_systems(
+            Update,
+            (
+                load_reference.run_if(resource_exists::<LoadReference>),
+                update_reference.run_if(resource_exists::<ReferenceModel>),
+            )
+                .chain()
+                .after(ui_system),
+        )
         .add_systems(
             Update,
             pan_orbit_camera.run_if(resource_exists::<EnablePanOrbit>),
         )
-        .add_systems(Update, render.run_if(resource_exists::<ForceRefresh>))
+        .add_systems(
+            Update,
+            (update_reports, render)
+                .chain()
+                .run_if(resource_exists::<ForceRefresh>),
+        )
         .run();
 }
@@ -0,0 +1,242 @@
+use super::{Label, Parsed};
+use bevy::math::{DVec2, Vec2};
+use core::f64::consts::TAU;
+use std::ops::Range;
+
+// flatter than this and it's a line, firmware gets imprecise with huge arcs
+const MAX_RADIUS: f64 = 1000.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Arc {
+    center: Vec2,
+    // G3, as opposed to G2
+    ccw: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArcStats {
+    // move lines before and after fitting
+    pub moves: usize,
+    pub emitted: usize,
+    pub arcs: usize,
+}
+
+impl ArcStats {
+    // how many times fewer moves are sent
+    pub fn ratio(&self) -> f32 {
+        self.moves as f32 / self.emitted.max(1) as f32
+    }
+}
+
+// the circle through three points
+fn circle(a: DVec2, b: DVec2, c: DVec2) -> Option<(DVec2, f64)> {
+    let d = 2.0 * (a.x * (b.y - c.y) + b.x * (c.y - a.y) + c.x * (a.y - b.y));
+    if d.abs() < 1e-9 {
+        return None;
+    }
+    let (a2, b2, c2) = (a.length_squared(), b.length_squared(), c.length_squared());
+    let center = DVec2::new(
+        (a2 * (b.y - c.y) + b2 * (c.y - a.y) + c2 * (a.y - b.y)) / d,
+        (a2 * (c.x - b.x) + b2 * (a.x - c.x) + c2 * (b.x - a.x)) / d,
+    );
+    Some((center, center.distance(a)))
+}
+
+// an arc every point and every segment of the polyline stays within `tolerance` of,
+// turning the same way all along and less than a full circle
+fn fit(points: &[DVec2], tolerance: f64) -> Option<Arc> {
+    if points.len() < 4 {
+        return None;
+    }
+    let (first, mid, last) = (
+        points[0],
+        points[points.len() / 2],
+        points[points.len() - 1],
+    );
+    let (center, radius) = circle(first, mid, last)?;
+    if radius > MAX_RADIUS {
+        return None;
+    }
+    let ccw = (mid - first).perp_dot(last - mid) > 0.0;
+    let mut sweep = 0.0;
+    for w in points.windows(2) {
+        let (a, b) = (w[0] - center, w[1] - center);
+        let turn = a.perp_dot(b);
+        if (turn > 0.0) != ccw || (b.length() - radius).abs() > tolerance {
+            return None;
+        }
+        // how far the middle of the segment cuts inside the arc
+        if radius - ((w[0] + w[1]) / 2.0).distance(center) > tolerance {
+            return None;
+        }
+        sweep += a.angle_between(b).abs();
+    }
+    (sweep < TAU * 0.99).then_some(Arc {
+        center: center.as_vec2(),
+        ccw,
+    })
+}
+
+impl Parsed {
+    // line ranges of planar extrusions that can be printed as one arc each
+    fn fit_arcs(&self, tolerance: f32) -> Vec<(Range<usize>, Arc)> {
+        let mut out = Vec::new();
+        // positions in `lines` of the moves of the current run
+        let mut run: Vec<usize> = Vec::new();
+        for i in 0..=self.lines.len() {
+            let v = self.lines.get(i).and_then(|id| self.vertices.get(id));
+            let joins = v.is_some_and(|v| {
+                v.label == Label::PlanarExtrustion
+                    && match run.last() {
+                        Some(last) => {
+                            let prev = self.vertices[&self.lines[*last]];
+                            *last + 1 == i && prev.to.z == v.to.z && prev.to.f == v.to.f
+                        }
+                        None => true,
+                    }
+            });
+            if joins {
+                run.push(i);
+                continue;
+            }
+            self.fit_run(&run, tolerance as f64, &mut out);
+            run.clear();
+            if v.is_some_and(|v| v.label == Label::PlanarExtrustion) {
+                run.push(i);
+            }
+        }
+        out
+    }
+    // greedily take the longest arc from the start of the run, then carry on after it
+    fn fit_run(&self, run: &[usize], tolerance: f64, out: &mut Vec<(Range<usize>, Arc)>) {
+        let Some(first) = run.first() else {
+            return;
+        };
+        let start = self.vertices[&self.lines[*first]].get_from(self);
+        let points: Vec<DVec2> = std::iter::once(DVec2::new(start.x as f64, start.y as f64))
+            .chain(run.iter().map(|i| {
+                let to = self.vertices[&self.lines[*i]].to;
+                DVec2::new(to.x as f64, to.y as f64)
+            }))
+            .collect();
+        let mut i = 0;
+        while i + 3 < points.len() {
+            let mut best = None;
+            let mut j = i + 3;
+            while j < points.len() {
+                match fit(&points[i..=j], tolerance) {
+                    Some(arc) => best = Some((j, arc)),
+                    None => break,
+                }
+                j += 1;
+            }
+            match best {
+                Some((j, arc)) => {
+                    // points[k] is where run[k - 1] ends
+                    out.push((run[i]..run[j - 1] + 1, arc));
+                    i = j;
+                }
+                None => i += 1,
+            }
+        }
+    }
+    // the print as gcode, with runs of short extrusions that follow a circle to within
+    // `tolerance` mm sent as G2/G3 arcs. each arc extrudes what its moves did together
+    pub fn emit_arcs(&self, tolerance: f32) -> (String, ArcStats) {
+        use super::emit::Emit;
+        let arcs = self.fit_arcs(tolerance);
+        let mut stats = ArcStats {
+            moves: self.vertices.len(),
+            emitted: self.vertices.len(),
+            arcs: arcs.len(),
+        };
+        let mut out = self.emit_header();
+        let mut arcs = arcs.into_iter().peekable();
+        let mut i = 0;
+        while i < self.lines.len() {
+            if let Some((range, arc)) = arcs.next_if(|(range, _)| range.start == i) {
+                let first = self.vertices[&self.lines[range.start]];
+                let last = self.vertices[&self.lines[range.end - 1]];
+                let from = first.get_from(self);
+                let e: f32 = self.lines[range.clone()]
+                    .iter()
+                    .map(|id| self.vertices[id].to.e)
+                    .sum();
+                out += &format!(
+                    "G{} X{} Y{} I{} J{} E{} ",
+                    if arc.ccw { 3 } else { 2 },
+                    last.to.x,
+                    last.to.y,
+                    arc.center.x - from.x,
+                    arc.center.y - from.y,
+                    e
+                );
+                if from.f != first.to.f {
+                    out += &format!("F{} ", first.to.f);
+                }
+                out += "\n";
+                stats.emitted -= range.len() - 1;
+                i = range.end;
+                continue;
+            }
+            let id = &self.lines[i];
+            if let Some(v) = self.vertices.get(id) {
+                out += &v.emit(self, false);
+            } else {
+                out += &self.instructions[id].emit(self, false);
+            }
+            i += 1;
+        }
+        (out, stats)
+    }
+    pub fn write_arcs_to_file(
+        &self,
+        path: &str,
+        tolerance: f32,
+    ) -> Result<ArcStats, std::io::Error> {
+        let (out, stats) = self.emit_arcs(tolerance);
+        std::fs::write(path, out)?;
+        println!(
+            "save successful, {} arcs, {:.1}x fewer moves",
+            stats.arcs,
+            stats.ratio()
+        );
+        Ok(stats)
+    }
+}
+
+#[test]
+fn circle_to_arc() {
+    let mut gcode = String::from("G28\nG1 X60 Y50 Z0.2 F1200\n");
+    for k in 1..=36 {
+        let a = k as f32 * TAU as f32 / 36.0;
+        gcode += &format!(
+            "G1 X{} Y{} E0.1\n",
+            50.0 + 10.0 * a.cos(),
+            50.0 + 10.0 * a.sin()
+        );
+    }
+    gcode += "G1 X70 Y70";
+    let gcode = Parsed::build(&gcode, true).expect("failed to parse");
+    let (out, stats) = gcode.emit_arcs(0.05);
+    assert_eq!(stats.arcs, 1);
+    assert!(stats.ratio() > 5.0);
+    let arc = out.lines().find(|l| l.starts_with("G3")).expect("no arc");
+    assert!(arc.contains("I-10 J0"));
+    // flow is kept
+    let e: f32 = out
+        .lines()
+        .filter_map(|l| l.split_whitespace().find_map(|w| w.strip_prefix('E')))
+        .map(|e| e.parse::<f32>().unwrap())
+        .sum();
+    assert!((e - 3.6).abs() < 1e-4);
+    // a straight line is left alone
+    let gcode = "G28
+    G1 X10 Y10 Z0.2
+    G1 X20 E1
+    G1 X30 E1
+    G1 X40 E1
+    G1 X50 E1";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    assert_eq!(gcode.emit_arcs(0.05).1.arcs, 0);
+}
@@ -0,0 +1,224 @@
+use super::warp::HeightMap;
+use super::{Id, Label, Parsed};
+use bevy::math::{Vec2, Vec3};
+use std::collections::{HashMap, HashSet};
+
+fn numbers(line: &str) -> Result<Vec<f32>, std::num::ParseFloatError> {
+    line.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.parse::<f32>())
+        .collect()
+}
+
+// whether klipper saved the section, its fields and its points
+type Section = (bool, HashMap<String, String>, Vec<Vec<f32>>);
+
+// `[bed_mesh <profile>]` sections of printer.cfg, with or without the `#*#` prefix.
+// the config section only says how to probe, so the first one with points is used,
+// a profile klipper saved before anything else
+fn klipper(text: &str) -> Result<HeightMap, Box<dyn std::error::Error>> {
+    let mut sections: Vec<Section> = Vec::new();
+    let mut in_mesh = false;
+    let mut in_points = false;
+    for line in text.lines() {
+        let line = line.trim();
+        let saved = line.starts_with("#*#");
+        let line = line.trim_start_matches("#*#").trim();
+        if line.starts_with('[') {
+            in_mesh = line.starts_with("[bed_mesh");
+            in_points = false;
+            if in_mesh {
+                sections.push((saved, HashMap::new(), Vec::new()));
+            }
+            continue;
+        }
+        let Some((_, fields, rows)) = sections.last_mut().filter(|_| in_mesh) else {
+            continue;
+        };
+        if line.is_empty() {
+            continue;
+        }
+        match line.split_once('=') {
+            Some((key, value)) => {
+                let (key, value) = (key.trim(), value.trim());
+                in_points = key == "points";
+                if in_points && !value.is_empty() {
+                    rows.push(numbers(value)?);
+                }
+                fields.insert(key.to_string(), value.to_string());
+            }
+            None if in_points => rows.push(numbers(line)?),
+            None => {}
+        }
+    }
+    let probed = || sections.iter().filter(|(_, _, rows)| !rows.is_empty());
+    let (_, fields, rows) = probed()
+        .find(|(saved, _, _)| *saved)
+        .or_else(|| probed().next())
+        .ok_or("no [bed_mesh] section has any points")?;
+    let field = |key: &str| -> Result<f32, Box<dyn std::error::Error>> {
+        let value = fields.get(key).ok_or(format!("bed mesh has no {}", key))?;
+        Ok(value.parse::<f32>()?)
+    };
+    let min = Vec2::new(field("min_x")?, field("min_y")?);
+    let max = Vec2::new(field("max_x")?, field("max_y")?);
+    let cols = rows.first().map_or(0, Vec::len);
+    HeightMap::new(cols, rows.len(), rows.concat(), min, max)
+}
+
+// a line of the console log without the echo: or recv: prefix hosts add
+fn console(line: &str) -> &str {
+    line.rsplit(':').next().unwrap_or(line).trim()
+}
+
+// the column indices above a marlin grid, 0 1 2 ...
+fn grid_header(line: &str) -> bool {
+    numbers(line).is_ok_and(|v| v.len() > 1 && v.iter().enumerate().all(|(i, n)| *n == i as f32))
+}
+
+// marlin says what it printed, or the grid shows by its header and the row 0 under it
+fn is_marlin(text: &str) -> bool {
+    let lines: Vec<&str> = text
+        .lines()
+        .map(console)
+        .filter(|l| !l.is_empty())
+        .collect();
+    text.contains("Leveling Grid")
+        || lines.windows(2).any(|w| {
+            let cols = numbers(w[0]).map_or(0, |v| v.len());
+            grid_header(w[0]) && numbers(w[1]).is_ok_and(|v| v.len() == cols + 1 && v[0] == 0.0)
+        })
+}
+
+// the grid marlin prints for `M420 V`, a header of column indices then one row per line
+// starting with its index. ubl prints the rows from the back of the bed
+fn marlin(text: &str, min: Vec2, max: Vec2) -> Result<HeightMap, Box<dyn std::error::Error>> {
+    let mut rows: Vec<(i32, Vec<f32>)> = Vec::new();
+    let mut header = false;
+    for line in text.lines() {
+        let line = console(line);
+        if !header && grid_header(line) {
+            header = true;
+            continue;
+        }
+        // text around the grid
+        let Some((index, values)) = line.split_once(char::is_whitespace) else {
+            continue;
+        };
+        let Ok(index) = index.parse::<i32>() else {
+            continue;
+        };
+        let values = numbers(values).map_err(|_| format!("bad mesh row: {}", line))?;
+        rows.push((index, values));
+    }
+    rows.sort_by_key(|(index, _)| *index);
+    let cols = rows.first().map_or(0, |(_, r)| r.len());
+    let count = rows.len();
+    let values = rows.into_iter().flat_map(|(_, r)| r).collect();
+    HeightMap::new(cols, count, values, min, max)
+}
+
+// read a probed mesh saved by klipper, printed by marlin, or written as csv.
+// marlin and csv meshes don't say where they were probed, they span `min` to `max`
+pub fn read_bed_mesh(
+    text: &str,
+    min: Vec2,
+    max: Vec2,
+) -> Result<HeightMap, Box<dyn std::error::Error>> {
+    if text.contains("[bed_mesh") {
+        klipper(text)
+    } else if is_marlin(text) {
+        marlin(text, min, max)
+    } else {
+        HeightMap::from_csv(text, min, max)
+    }
+}
+
+impl Parsed {
+    // add the mesh height under every move to the first `layers` layers, then fade it out
+    // over the next `fade` mm. moves are split to `max_len` first so they follow the mesh
+    pub fn bake_bed_mesh(&mut self, mesh: &HeightMap, layers: usize, fade: f32, max_len: f32) {
+        let Some(top) = self
+            .layers()
+            .get(layers.max(1) - 1)
+            .or(self.layers().last())
+            .map(|l| l.z)
+        else {
+            return;
+        };
+        let end = top + fade.max(0.0);
+        let ids: Vec<Id> = self
+            .vertices
+            .values()
+            .filter(|v| v.label != Label::Home && v.to.z < end + 1e-4)
+            .map(|v| v.id)
+            .collect();
+        let mut selection: HashSet<Id> = ids.iter().copied().collect();
+        selection.extend(self.subdivide_to(&ids, max_len));
+        self.move_vertices(&selection, |p| {
+            let weight = if p.z <= top + 1e-4 {
+                1.0
+            } else {
+                (1.0 - (p.z - top) / fade).clamp(0.0, 1.0)
+            };
+            p + Vec3::Z * mesh.sample(Vec2::new(p.x, p.y)) * weight
+        });
+        self.assign_shapes();
+    }
+}
+
+#[test]
+fn formats_and_fade() {
+    // the config section comes first but only the saved profile has points
+    let klipper = "[bed_mesh]
+    probe_count = 3, 3
+    mesh_min = 10, 10
+
+    #*# [bed_mesh default]
+    #*# version = 1
+    #*# points =
+    #*# 	0.0, 0.1
+    #*# 	0.2, 0.3
+    #*# x_count = 2
+    #*# y_count = 2
+    #*# min_x = 0.0
+    #*# max_x = 100.0
+    #*# min_y = 0.0
+    #*# max_y = 100.0
+    #*#
+    #*# [probe]";
+    let mesh = read_bed_mesh(klipper, Vec2::ZERO, Vec2::ONE).expect("klipper mesh");
+    assert!((mesh.sample(Vec2::new(100.0, 0.0)) - 0.1).abs() < 1e-6);
+    let marlin = "Bilinear Leveling Grid:
+          0      1
+     0 +0.000 +0.100
+     1 +0.200 +0.300";
+    let mesh = read_bed_mesh(marlin, Vec2::ZERO, Vec2::splat(100.0)).expect("marlin mesh");
+    assert!((mesh.sample(Vec2::new(0.0, 100.0)) - 0.2).abs() < 1e-6);
+    let broken = "0 1\n0 +0.000 +0.100\n1 ===== +0.300";
+    assert!(read_bed_mesh(broken, Vec2::ZERO, Vec2::ONE).is_err());
+    // whitespace csv that doesn't look like a grid
+    let csv = read_bed_mesh("0.1 0.2\n0.3 0.4", Vec2::ZERO, Vec2::ONE).expect("csv mesh");
+    assert!((csv.sample(Vec2::ZERO) - 0.1).abs() < 1e-6);
+    // one full layer, then half the offset at 0.4 with a 0.4mm fade
+    let gcode = "G28
+    G1 X10 Y10 Z0.2
+    G1 X20 E1
+    G1 Z0.4
+    G1 X10 E1
+    G1 Z0.6
+    G1 X20 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let flat = HeightMap::from_csv("0.1,0.1\n0.1,0.1", Vec2::ZERO, Vec2::ONE).expect("csv");
+    gcode.bake_bed_mesh(&flat, 1, 0.4, 100.0);
+    let zs: Vec<f32> = gcode
+        .lines
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .filter(|v| v.extrusion_move())
+        .map(|v| v.to.z)
+        .collect();
+    assert!((zs[0] - 0.3).abs() < 1e-5);
+    assert!((zs[1] - 0.45).abs() < 1e-5);
+    assert!((zs[2] - 0.6).abs() < 1e-5);
+}
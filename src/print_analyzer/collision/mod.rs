@@ -0,0 +1,119 @@
+use super::flow::Bead;
+use super::spatial::Segment;
+use super::{Id, Label, Parsed};
+use bevy::math::Vec2;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Collision {
+    // the travel move
+    pub id: Id,
+    // mm of the travel that passes over printed beads
+    pub length: f32,
+    // height of the highest layer crossed
+    pub layer: f32,
+}
+
+impl Parsed {
+    // travel moves that pass over material printed on the current or previous layer
+    // without lifting at least `clearance` mm above it
+    pub fn travel_collisions(&self, beads: &HashMap<Id, Bead>, clearance: f32) -> Vec<Collision> {
+        let zs = self.extrusion_zs();
+        let widest = beads.values().map(|b| b.width).fold(0.0, f32::max);
+        let mut out = Vec::new();
+        for v in self.vertices.values() {
+            if v.label != Label::TravelMove {
+                continue;
+            }
+            let from = v.get_from(self);
+            let (a, b) = (Vec2::new(from.x, from.y), Vec2::new(v.to.x, v.to.y));
+            let len = a.distance(b);
+            if len < f32::EPSILON {
+                continue;
+            }
+            // the two highest layers at or below the travel
+            let top = zs.partition_point(|z| *z <= v.to.z + 1e-4);
+            let nearby: Vec<(f32, Vec<Segment>)> = zs[top.saturating_sub(2)..top]
+                .iter()
+                .rev()
+                .filter(|z| v.to.z - *z < clearance)
+                .map(|z| {
+                    let segs = self
+                        .index
+                        .near_segment(*z, a, b, widest / 2.0)
+                        .into_iter()
+                        // only what has been printed by the time the travel happens
+                        .filter(|id| self.vertices[id].count < v.count)
+                        .filter_map(|id| {
+                            let bead = beads.get(&id)?;
+                            let (from, to) = self.index.segment(&id)?;
+                            Some(Segment {
+                                a: from.truncate(),
+                                b: to.truncate(),
+                                half_width: bead.width / 2.0,
+                            })
+                        })
+                        .collect();
+                    (*z, segs)
+                })
+                .collect();
+            if nearby.iter().all(|(_, s)| s.is_empty()) {
+                continue;
+            }
+            // sample the travel finely enough not to skip over a bead
+            let min_width = nearby
+                .iter()
+                .flat_map(|(_, s)| s.iter().map(|s| s.half_width))
+                .fold(f32::INFINITY, f32::min);
+            let step = (min_width / 2.0).clamp(0.01, 0.1);
+            let samples = (len / step).ceil() as usize;
+            let mut crossing = 0;
+            let mut hit_layer = f32::NEG_INFINITY;
+            for i in 0..samples {
+                let p = a.lerp(b, (i as f32 + 0.5) / samples as f32);
+                for (z, segs) in &nearby {
+                    // leaving the bead just printed or landing next to the next one isn't a crossing
+                    let hit = |s: &Segment| {
+                        s.dist(p) <= s.half_width
+                            && p.distance(a) > 2.0 * s.half_width
+                            && p.distance(b) > 2.0 * s.half_width
+                    };
+                    if segs.iter().any(hit) {
+                        crossing += 1;
+                        hit_layer = hit_layer.max(*z);
+                        break;
+                    }
+                }
+            }
+            if crossing > 0 {
+                out.push(Collision {
+                    id: v.id,
+                    length: len * crossing as f32 / samples as f32,
+                    layer: hit_layer,
+                });
+            }
+        }
+        out.sort_by_key(|c| self.vertices[&c.id].count);
+        out
+    }
+}
+
+#[test]
+fn travel_over_wall() {
+    use super::usage::Filament;
+    // a wall along y at x = 20, then a travel straight across it with and without a hop
+    let gcode = "G28
+    G1 X20 Y10 Z0.2 F1200
+    G1 Y30 E1
+    G1 X10 Y20
+    G1 X30 Y20
+    G1 Z0.6
+    G1 X10 Y20";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let beads = gcode.beads(&Filament::default());
+    let collisions = gcode.travel_collisions(&beads, 0.1);
+    assert_eq!(collisions.len(), 1);
+    let width = beads[&gcode.lines[2]].width;
+    assert!((collisions[0].length - width).abs() < 0.1);
+    assert_eq!(collisions[0].id, gcode.lines[4]);
+}
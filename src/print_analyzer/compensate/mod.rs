@@ -0,0 +1,75 @@
+use super::{Id, Parsed};
+use bevy::math::{Affine3A, Vec2, Vec3};
+use core::f32::consts::FRAC_PI_2;
+use std::collections::HashSet;
+
+// skew factors as given to klipper's SET_SKEW or marlin's M852 I J K
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Skew {
+    pub xy: f32,
+    pub xz: f32,
+    pub yz: f32,
+}
+
+// the factor for one plane from the diagonals `ac` and `bd` and side `ad` measured on a
+// printed square, as klipper's skew_correction works it out
+pub fn skew_factor(ac: f32, bd: f32, ad: f32) -> f32 {
+    let side = (2.0 * ac * ac + 2.0 * bd * bd - 4.0 * ad * ad).sqrt() / 2.0;
+    (FRAC_PI_2 - ((ac * ac - side * side - ad * ad) / (2.0 * side * ad)).acos()).tan()
+}
+
+impl Parsed {
+    // undo a measured skew and how much the print shrinks in x and y, as fractions of
+    // its size, about `origin`. z is left alone so layers keep their height
+    pub fn compensate(
+        &mut self,
+        selection: &HashSet<Id>,
+        origin: Vec3,
+        skew: Skew,
+        shrinkage: Vec2,
+    ) {
+        let scale = Vec2::ONE / (Vec2::ONE - shrinkage).max(Vec2::splat(f32::EPSILON));
+        let scale = Affine3A::from_scale(scale.extend(1.0));
+        // the firmware correction, what gets printed is skewed back onto the model
+        let skew = Affine3A::from_cols(
+            Vec3::X.into(),
+            Vec3::new(-skew.xy, 1.0, 0.0).into(),
+            Vec3::new(-(skew.xz - skew.xy * skew.yz), -skew.yz, 1.0).into(),
+            Vec3::ZERO.into(),
+        );
+        let selection = if selection.is_empty() {
+            self.lines.iter().copied().collect()
+        } else {
+            selection.clone()
+        };
+        self.apply_transform(&selection, skew * scale, origin, false);
+    }
+}
+
+#[test]
+fn skew_and_shrink() {
+    // a square prints square
+    assert!(skew_factor(2f32.sqrt() * 100.0, 2f32.sqrt() * 100.0, 100.0).abs() < 1e-5);
+    assert!(skew_factor(142.0, 140.0, 100.0) > 0.0);
+    let gcode = "G28
+    G1 X0 Y0 Z0.2
+    G1 X0 Y10 E1
+    G1 Z10.2
+    G1 X10 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let l = gcode.lines.clone();
+    let skew = Skew {
+        xy: 0.1,
+        xz: 0.0,
+        yz: 0.01,
+    };
+    gcode.compensate(&HashSet::new(), Vec3::ZERO, skew, Vec2::new(0.5, 0.0));
+    let to = |i: usize| gcode.vertices[&l[i]].to;
+    assert_eq!(to(1).z, 0.2);
+    // leaning back against the skew, with nothing to make up for in y
+    assert!((to(2).x + 1.0 - 0.002 * 0.1).abs() < 1e-4);
+    assert!((to(2).y - 10.0 + 0.002).abs() < 1e-4);
+    assert_eq!(to(4).z, 10.2);
+    // twice as wide, twice the plastic
+    assert!((to(4).e - 2.0).abs() < 1e-3);
+}
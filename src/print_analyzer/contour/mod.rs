@@ -0,0 +1,215 @@
+use super::flow::Bead;
+use super::spatial::Segment;
+use super::{Id, Parsed};
+use bevy::math::Vec2;
+use std::collections::HashMap;
+
+// mm between the start and end of a run of extrusions for it to count as closed
+pub(super) const CLOSE: f32 = 0.5;
+
+// an uninterrupted run of extrusions within a shape
+#[derive(Clone, Debug, PartialEq)]
+pub struct Path {
+    pub shape: Id,
+    pub layer: usize,
+    // extrusion moves in print order
+    pub vertices: Vec<Id>,
+    // where the first move starts, then the end of every move
+    pub points: Vec<Vec2>,
+    pub closed: bool,
+}
+
+impl Path {
+    pub fn length(&self) -> f32 {
+        self.points.windows(2).map(|w| w[0].distance(w[1])).sum()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    // bounds material on its inside
+    Outer,
+    // bounds a void on its inside
+    Hole,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Polygon {
+    // index into the paths it was built from
+    pub path: usize,
+    // the closing point isn't repeated
+    pub points: Vec<Vec2>,
+    // positive when printed counter clockwise
+    pub area: f32,
+    pub orientation: Orientation,
+}
+
+impl Polygon {
+    pub(super) fn build(path: usize, points: &[Vec2]) -> Polygon {
+        let mut points = points.to_vec();
+        if points.len() > 1 && points[0].distance(points[points.len() - 1]) < CLOSE {
+            points.pop();
+        }
+        let n = points.len();
+        let area = (0..n)
+            .map(|i| points[i].perp_dot(points[(i + 1) % n]))
+            .sum::<f32>()
+            / 2.0;
+        Polygon {
+            path,
+            points,
+            area,
+            orientation: Orientation::Outer,
+        }
+    }
+    // even-odd rule
+    pub fn contains(&self, p: Vec2) -> bool {
+        let n = self.points.len();
+        let mut inside = false;
+        for i in 0..n {
+            let (a, b) = (self.points[i], self.points[(i + n - 1) % n]);
+            if (a.y > p.y) != (b.y > p.y) && p.x < (b.x - a.x) * (p.y - a.y) / (b.y - a.y) + a.x {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+    fn edges(&self) -> impl Iterator<Item = Segment> + '_ {
+        let n = self.points.len();
+        (0..n).map(move |i| Segment {
+            a: self.points[i],
+            b: self.points[(i + 1) % n],
+            half_width: 0.0,
+        })
+    }
+    // closest approach of any of our points to the other polygon's outline
+    fn gap(&self, other: &Polygon) -> f32 {
+        self.points
+            .iter()
+            .flat_map(|p| other.edges().map(move |e| e.dist(*p)))
+            .fold(f32::INFINITY, f32::min)
+    }
+}
+
+impl Parsed {
+    // split every shape into runs of extrusions, and tell loops from open paths
+    pub fn paths(&self) -> Vec<Path> {
+        let mut out = Vec::new();
+        for shape in &self.shapes {
+            let mut run: Vec<Id> = Vec::new();
+            for line in &shape.lines {
+                let Some(v) = self.vertices.get(line) else {
+                    continue;
+                };
+                if v.extrusion_move() {
+                    run.push(v.id);
+                } else if v.to.dist(&v.get_from(self)) > f32::EPSILON {
+                    // any other move that goes somewhere breaks the run
+                    self.close_path(shape.id, std::mem::take(&mut run), &mut out);
+                }
+            }
+            self.close_path(shape.id, run, &mut out);
+        }
+        out
+    }
+    fn close_path(&self, shape: Id, vertices: Vec<Id>, out: &mut Vec<Path>) {
+        let Some(first) = vertices.first() else {
+            return;
+        };
+        let from = self.vertices[first].get_from(self);
+        let mut points = vec![Vec2::new(from.x, from.y)];
+        points.extend(vertices.iter().map(|id| {
+            let to = self.vertices[id].to;
+            Vec2::new(to.x, to.y)
+        }));
+        let path = Path {
+            shape,
+            layer: self.layer_of(first).map_or(0, |l| l.index),
+            closed: false,
+            vertices,
+            points,
+        };
+        let gap = path.points[0].distance(path.points[path.points.len() - 1]);
+        let area = Polygon::build(0, &path.points).area.abs();
+        let closed = path.points.len() > 3 && gap < CLOSE && area > CLOSE * CLOSE;
+        out.push(Path { closed, ..path });
+    }
+    // the closed paths of every layer as polygons, indexed by layer.
+    // a loop right next to the loop around it is another wall of the same outline,
+    // one further in starts a hole, or an island inside a hole
+    pub fn polygons(&self, paths: &[Path], beads: &HashMap<Id, Bead>) -> Vec<Vec<Polygon>> {
+        let count = self.layers().len().max(1);
+        let mut out: Vec<Vec<Polygon>> = vec![Vec::new(); count];
+        for (i, path) in paths.iter().enumerate() {
+            if path.closed {
+                out[path.layer.min(count - 1)].push(Polygon::build(i, &path.points));
+            }
+        }
+        for polygons in out.iter_mut() {
+            // containers come before what they contain
+            polygons.sort_by(|a, b| b.area.abs().total_cmp(&a.area.abs()));
+            for i in 0..polygons.len() {
+                let start = polygons[i].points[0];
+                let Some(parent) = (0..i).rev().find(|j| polygons[*j].contains(start)) else {
+                    continue;
+                };
+                let widths: Vec<f32> = paths[polygons[parent].path]
+                    .vertices
+                    .iter()
+                    .filter_map(|id| beads.get(id).map(|b| b.width))
+                    .collect();
+                let width = if widths.is_empty() {
+                    0.5
+                } else {
+                    widths.iter().sum::<f32>() / widths.len() as f32
+                };
+                let adjacent = polygons[i].gap(&polygons[parent]) <= 1.5 * width;
+                polygons[i].orientation = match (polygons[parent].orientation, adjacent) {
+                    (o, true) => o,
+                    (Orientation::Outer, false) => Orientation::Hole,
+                    (Orientation::Hole, false) => Orientation::Outer,
+                };
+            }
+        }
+        out
+    }
+}
+
+#[test]
+fn walls_and_hole() {
+    use super::usage::Filament;
+    // an outer wall with an inner wall, a hole in the middle and a line of infill
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    G1 Y30 E1
+    G1 X10 E1
+    G1 Y10 E1
+    G1 X10.5 Y10.5
+    G1 X29.5 E1
+    G1 Y29.5 E1
+    G1 X10.5 E1
+    G1 Y10.5 E1
+    G1 X18 Y18
+    G1 Y22 E0.2
+    G1 X22 E0.2
+    G1 Y18 E0.2
+    G1 X18 E0.2
+    G1 X12 Y12
+    G1 X16 E0.2";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let paths = gcode.paths();
+    let closed: Vec<bool> = paths.iter().map(|p| p.closed).collect();
+    assert_eq!(closed, vec![true, true, true, false]);
+    let beads = gcode.beads(&Filament::default());
+    let polygons = &gcode.polygons(&paths, &beads)[0];
+    let orientation = |path: usize| {
+        polygons
+            .iter()
+            .find(|p| p.path == path)
+            .map(|p| p.orientation)
+    };
+    assert_eq!(orientation(0), Some(Orientation::Outer));
+    assert_eq!(orientation(1), Some(Orientation::Outer));
+    assert_eq!(orientation(2), Some(Orientation::Hole));
+}
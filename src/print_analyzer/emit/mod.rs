@@ -46,7 +46,7 @@ impl Emit for Pos {
 }
 impl Emit for Vertex {
     fn emit(&self, parsed: &Parsed, debug: bool) -> String {
-        if self.to == Pos::home() && self.prev.is_none() {
+        if self.label == Label::Home {
             return "G28\n".to_string();
         }
         let from = self.get_from(parsed);
@@ -78,8 +78,9 @@ impl Emit for Vertex {
         out
     }
 }
-impl Emit for Parsed {
-    fn emit(&self, _parsed: &Parsed, debug: bool) -> String {
+impl Parsed {
+    // positioning modes the rest of the file is written in
+    pub(super) fn emit_header(&self) -> String {
         let mut out = String::new();
 
         if self.rel_xyz {
@@ -92,6 +93,12 @@ impl Emit for Parsed {
         } else {
             out += "M82\n";
         }
+        out
+    }
+}
+impl Emit for Parsed {
+    fn emit(&self, _parsed: &Parsed, debug: bool) -> String {
+        let mut out = self.emit_header();
 
         for line in &self.lines {
             if let Some(v) = self.vertices.get(line) {
@@ -1,15 +1,33 @@
 use super::*;
 
+// slicer comments that mark layer changes, kept so layers can be found from them
+fn layer_comment(line: &str) -> bool {
+    let Some(comment) = line.trim().strip_prefix(';') else {
+        return false;
+    };
+    let comment = comment.trim().to_uppercase();
+    ["LAYER_CHANGE", "LAYER:", "Z:", "HEIGHT:"]
+        .iter()
+        .any(|m| comment.starts_with(m))
+}
+
+// ignore ';' comments other than layer markers
+fn strip_comment(line: &str) -> String {
+    if layer_comment(line) {
+        return line.trim().to_string();
+    }
+    line.split(';').next().unwrap().to_string()
+}
+
 pub fn parse_file(path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>> {
     let out = String::from_utf8(std::fs::read(path)?)?
         .lines()
         .filter_map(|s| {
-            // ignore ';' comments
-            let s = s.split(';').next().unwrap();
+            let s = strip_comment(s);
             if s.is_empty() {
                 None
             } else {
-                Some(s.to_string())
+                Some(s)
             }
         })
         .collect();
@@ -17,15 +35,15 @@ pub fn parse_file(path: &str) -> Result<Vec<String>, Box<dyn std::error::Error>>
 }
 
 pub fn parse_str(str: &str) -> Vec<String> {
-    String::from(str)
-        .split("\n")
-        .map(|s| s.split(';').next().unwrap().to_string())
-        .collect()
+    String::from(str).split("\n").map(strip_comment).collect()
 }
 
 pub fn split_line(line: &str) -> Vec<Word> {
     // FIXME: G28 W bug
     let mut out = Vec::new();
+    if line.starts_with(';') {
+        return Vec::from([Word('X', f32::NEG_INFINITY, Some(line.to_owned()))]);
+    }
     let words = line.split_whitespace();
     for word in words {
         let mut slice = word.chars();
@@ -0,0 +1,206 @@
+use super::planner::MachineLimits;
+use super::usage::Filament;
+use super::{Id, Label, Parsed, Vertex};
+use std::collections::HashMap;
+use std::f32::consts::PI;
+
+// geometry of the plastic laid down by a single extrusion move
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bead {
+    // mm of toolpath
+    pub length: f32,
+    // mm^3 of plastic
+    pub volume: f32,
+    // layer height in mm
+    pub height: f32,
+    // cross section area in mm^2
+    pub area: f32,
+    // mm
+    pub width: f32,
+    // volumetric flow in mm^3/s at the commanded feedrate
+    pub flow: f32,
+}
+
+impl Bead {
+    pub fn build(from: &Vertex, to: &Vertex, height: f32, filament: &Filament) -> Option<Bead> {
+        let length = from.to.dist(&to.to);
+        if to.to.e <= 0.0 || length < f32::EPSILON || height <= 0.0 {
+            return None;
+        }
+        let volume = to.to.e * filament.area();
+        let area = volume / length;
+        // rounded rectangle: a rectangle of (w - h) x h with half circles of diameter h on the sides
+        let width = if area >= PI * height * height / 4.0 {
+            area / height + height * (1.0 - PI / 4.0)
+        } else {
+            // too little plastic to fill the layer height, treat it as a round strand
+            (4.0 * area / PI).sqrt()
+        };
+        let time = length / (to.to.f / 60.0);
+        let flow = if time.is_finite() && time > 0.0 {
+            volume / time
+        } else {
+            0.0
+        };
+        Some(Bead {
+            length,
+            volume,
+            height,
+            area,
+            width,
+            flow,
+        })
+    }
+}
+
+impl Parsed {
+    // distinct z heights of extrusion moves, sorted
+    pub(super) fn extrusion_zs(&self) -> Vec<f32> {
+        let mut zs: Vec<f32> = self
+            .vertices
+            .values()
+            .filter(|v| v.extrusion_move())
+            .map(|v| v.to.z)
+            .collect();
+        zs.sort_by(f32::total_cmp);
+        zs.dedup_by(|a, b| (*a - *b).abs() < 1e-4);
+        zs
+    }
+    // distance from z down to the next extrusion height below it, or to the bed
+    fn height_below(zs: &[f32], z: f32) -> f32 {
+        let i = zs.partition_point(|l| *l < z - 1e-4);
+        if i == 0 {
+            z
+        } else {
+            z - zs[i - 1]
+        }
+    }
+    pub fn beads(&self, filament: &Filament) -> HashMap<Id, Bead> {
+        let zs = self.extrusion_zs();
+        let mut out = HashMap::new();
+        for v in self.vertices.values() {
+            if !v.extrusion_move() {
+                continue;
+            }
+            let Some(prev) = self.lines.prev(&v.id).and_then(|p| self.vertices.get(&p)) else {
+                continue;
+            };
+            let height = Parsed::height_below(&zs, v.to.z);
+            if let Some(bead) = Bead::build(prev, v, height, filament) {
+                out.insert(v.id, bead);
+            }
+        }
+        out
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Violation {
+    // mm^3/s
+    Flow { flow: f32, max: f32 },
+    // mm/s along a single axis ('X', 'Y', 'Z', 'E') or the whole move ('F')
+    Feedrate { axis: char, speed: f32, max: f32 },
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FlowCheck {
+    // highest fraction of any limit used by each move, 1.0 and over is a violation
+    pub ratio: HashMap<Id, f32>,
+    // in line order
+    pub violations: Vec<(Id, Violation)>,
+}
+
+impl Parsed {
+    // check every move against the hotend flow limit and the machine feedrate limits,
+    // limits set by M203 or SET_VELOCITY_LIMIT in the file take effect where they appear
+    pub fn check_flow(
+        &self,
+        beads: &HashMap<Id, Bead>,
+        max_flow: f32,
+        defaults: &MachineLimits,
+    ) -> FlowCheck {
+        let mut limits = *defaults;
+        let mut out = FlowCheck::default();
+        for line in &self.lines {
+            if let Some(ins) = self.instructions.get(line) {
+                limits.apply(ins);
+                continue;
+            }
+            let Some(v) = self.vertices.get(line) else {
+                continue;
+            };
+            if v.label == Label::Home || !v.to.f.is_finite() {
+                continue;
+            }
+            let mut ratio: f32 = 0.0;
+            if let Some(bead) = beads.get(&v.id) {
+                ratio = ratio.max(bead.flow / max_flow);
+                if bead.flow > max_flow {
+                    let flow = bead.flow;
+                    out.violations.push((
+                        v.id,
+                        Violation::Flow {
+                            flow,
+                            max: max_flow,
+                        },
+                    ));
+                }
+            }
+            let from = v.get_from(self);
+            let (dx, dy, dz) = v.to - from;
+            let len = (dx * dx + dy * dy + dz * dz).sqrt();
+            let speed = v.to.f / 60.0;
+            let mut axes = vec![('F', speed, limits.max_velocity)];
+            if len > f32::EPSILON {
+                axes.extend([
+                    ('X', speed * dx.abs() / len, limits.max_feedrate[0]),
+                    ('Y', speed * dy.abs() / len, limits.max_feedrate[1]),
+                    ('Z', speed * dz.abs() / len, limits.max_feedrate[2]),
+                    ('E', speed * v.to.e.abs() / len, limits.max_feedrate[3]),
+                ]);
+            } else if v.to.e != 0.0 {
+                axes.push(('E', speed, limits.max_feedrate[3]));
+            }
+            for (axis, speed, max) in axes {
+                if speed <= 0.0 {
+                    continue;
+                }
+                ratio = ratio.max(speed / max);
+                if speed > max {
+                    out.violations
+                        .push((v.id, Violation::Feedrate { axis, speed, max }));
+                }
+            }
+            out.ratio.insert(v.id, ratio);
+        }
+        out
+    }
+}
+
+#[test]
+fn bead_geometry() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F1200\nG1 X20 E0.5\nG1 Z0.4\nG1 X10 E0.4\n";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let beads = gcode.beads(&Filament::default());
+    let first = beads[&gcode.lines[2]];
+    let second = beads[&gcode.lines[4]];
+    assert!((first.height - 0.2).abs() < 1e-5);
+    assert!((second.height - 0.2).abs() < 1e-5);
+    // 0.5mm of 1.75 filament over 10mm is about 0.12mm^2, a bead a little over 0.6mm wide
+    assert!((first.volume - 1.2026).abs() < 1e-3);
+    assert!((first.width - 0.644).abs() < 1e-3);
+    // 10mm at 20mm/s
+    assert!((first.flow - first.volume * 2.0).abs() < 1e-3);
+}
+
+#[test]
+fn flow_limit() {
+    // 0.2mm layer, 0.45mm wide bead at 200mm/s is about 18mm^3/s
+    let gcode = "G28\nG1 X10 Y10 Z0.2 F12000\nG1 X110 E3.3\nG1 X10 E3.3 F3000\n";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let beads = gcode.beads(&Filament::default());
+    let check = gcode.check_flow(&beads, 15.0, &MachineLimits::default());
+    assert_eq!(check.violations.len(), 1);
+    assert_eq!(check.violations[0].0, gcode.lines[2]);
+    assert!(check.ratio[&gcode.lines[3]] < 1.0);
+}
@@ -0,0 +1,233 @@
+use super::{Id, Instruction, Label, Parsed, Word};
+use std::collections::HashMap;
+use std::ops::Range;
+
+// mm a flat extrusion has to be above the layer to start a new one, when the file has
+// no markers
+const MIN_LAYER_STEP: f32 = 0.02;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layer {
+    pub index: usize,
+    // z the slicer printed the layer at, the most common extrusion z without markers
+    pub z: f32,
+    pub height: f32,
+    // positions in `Parsed::lines`
+    pub lines: Range<usize>,
+    // vertex counts
+    pub vertices: Range<u32>,
+    // indices into `Parsed::shapes`
+    pub shapes: Range<usize>,
+}
+
+enum Marker {
+    Change,
+    Z(f32),
+    Height(f32),
+}
+
+// layer change comments kept by the file reader, and the klipper layer macro
+fn marker(ins: &Instruction) -> Option<Marker> {
+    let Word(_, _, Some(raw)) = &ins.first_word else {
+        return None;
+    };
+    let raw = raw.trim().to_uppercase();
+    let Some(comment) = raw.strip_prefix(';') else {
+        let klipper = raw.starts_with("SET_PRINT_STATS_INFO") && raw.contains("CURRENT_LAYER");
+        return klipper.then_some(Marker::Change);
+    };
+    let comment = comment.trim();
+    if comment.starts_with("LAYER_CHANGE") || comment.starts_with("LAYER:") {
+        Some(Marker::Change)
+    } else if let Some(z) = comment.strip_prefix("Z:") {
+        z.trim().parse().ok().map(Marker::Z)
+    } else if let Some(h) = comment.strip_prefix("HEIGHT:") {
+        h.trim().parse().ok().map(Marker::Height)
+    } else {
+        None
+    }
+}
+
+// a layer while it's being collected
+#[derive(Default)]
+struct Pending {
+    start: usize,
+    z: Option<f32>,
+    height: Option<f32>,
+    // extrusion z in microns -> number of moves
+    zs: HashMap<i32, u32>,
+    vertices: Option<Range<u32>>,
+}
+
+impl Parsed {
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+    // layer a line is printed in
+    pub fn layer_of(&self, id: &Id) -> Option<&Layer> {
+        let pos = self.lines.position(id)?;
+        let i = self.layers.partition_point(|l| l.lines.end <= pos);
+        self.layers.get(i)
+    }
+    // every line of the layer a line is in
+    pub fn get_layer(&self, id: &Id) -> Vec<Id> {
+        self.layer_of(id)
+            .map(|l| self.lines[l.lines.clone()].to_vec())
+            .unwrap_or_default()
+    }
+    // split the file into layers at layer change markers. files without any are split
+    // where flat extrusions move higher, ignoring z hops, travel moves, and the rising
+    // moves of vase mode or non-planar prints
+    pub(super) fn assign_layers(&mut self) {
+        let markers = self
+            .lines
+            .iter()
+            .filter_map(|id| self.instructions.get(id))
+            .any(|ins| matches!(marker(ins), Some(Marker::Change)));
+        // position of the first line of every shape, empty shapes sit where the last one did
+        let mut shape_starts = Vec::with_capacity(self.shapes.len());
+        let mut last = 0;
+        for shape in &self.shapes {
+            last = shape
+                .lines
+                .first()
+                .and_then(|id| self.lines.position(id))
+                .unwrap_or(last);
+            shape_starts.push(last);
+        }
+        let mut layers = Vec::new();
+        let mut layer = Pending::default();
+        let mut start_z: Option<f32> = None;
+        for (pos, id) in self.lines.iter().enumerate() {
+            if let Some(ins) = self.instructions.get(id) {
+                match marker(ins) {
+                    Some(Marker::Change) if markers => {
+                        close_layer(&shape_starts, &mut layers, &mut layer, pos);
+                    }
+                    // only the ones heading the layer describe it
+                    Some(Marker::Z(z)) if layer.zs.is_empty() => layer.z = Some(z),
+                    Some(Marker::Height(h)) if layer.zs.is_empty() => layer.height = Some(h),
+                    _ => {}
+                }
+                continue;
+            }
+            let Some(v) = self.vertices.get(id) else {
+                continue;
+            };
+            if v.extrusion_move() {
+                if !markers {
+                    let flat = v.label == Label::PlanarExtrustion;
+                    match start_z {
+                        None => start_z = Some(v.to.z),
+                        // z holding a step higher starts a new layer
+                        Some(z) if flat && v.to.z >= z + MIN_LAYER_STEP => {
+                            close_layer(&shape_starts, &mut layers, &mut layer, pos);
+                            start_z = Some(v.to.z);
+                        }
+                        Some(_) => {}
+                    }
+                }
+                *layer
+                    .zs
+                    .entry((v.to.z * 1000.0).round() as i32)
+                    .or_default() += 1;
+            }
+            let range = layer.vertices.get_or_insert(v.count..v.count);
+            range.end = v.count + 1;
+        }
+        let end = self.lines.len();
+        close_layer(&shape_starts, &mut layers, &mut layer, end);
+        // trailing lines after the last extrusion belong to the last layer
+        if let Some(last) = layers.last_mut() {
+            last.lines.end = end;
+            last.shapes.end = shape_starts.len();
+        }
+        self.layers = layers;
+    }
+}
+
+// finish the pending layer at `end` and start the next one there.
+// layers without extrusions are folded into the next one
+fn close_layer(shape_starts: &[usize], layers: &mut Vec<Layer>, layer: &mut Pending, end: usize) {
+    if layer.zs.is_empty() {
+        return;
+    }
+    let pending = std::mem::take(layer);
+    layer.start = end;
+    let lines = pending.start..end;
+    // on a tie the lowest, where a ramp within the layer starts from
+    let common = pending
+        .zs
+        .iter()
+        .max_by_key(|(z, n)| (**n, -**z))
+        .map(|(z, _)| *z as f32 / 1000.0)
+        .unwrap_or_default();
+    let z = pending.z.unwrap_or(common);
+    let below = layers.last().map_or(0.0, |l: &Layer| l.z);
+    let height = pending.height.unwrap_or(z - below);
+    let first = shape_starts.partition_point(|p| *p < lines.start);
+    let last = shape_starts.partition_point(|p| *p < lines.end);
+    layers.push(Layer {
+        index: layers.len(),
+        z,
+        height,
+        lines,
+        vertices: pending.vertices.unwrap_or_default(),
+        shapes: first..last,
+    });
+}
+
+#[test]
+fn markers_and_fallback() {
+    // a z hop between the two layers shouldn't count as a layer
+    let plain = "G28
+    G1 X10 Y10 Z0.2
+    G1 X20 E1
+    G1 Z0.6
+    G1 X10 Y20
+    G1 Z0.2
+    G1 X20 E1
+    G1 Z0.4
+    G1 X10 E1
+    G1 Y10 E1";
+    let gcode = Parsed::build(plain, true).expect("failed to parse");
+    let layers = gcode.layers();
+    assert_eq!(layers.len(), 2);
+    assert_eq!((layers[0].z, layers[1].z), (0.2, 0.4));
+    assert!((layers[1].height - 0.2).abs() < 1e-4);
+    assert_eq!(layers[1].lines.end, gcode.lines.len());
+    // fine layers on a thick first layer
+    let fine = "G28\nG1 X10 Y10 Z0.3\nG1 X20 E1\nG1 Z0.35\nG1 X10 E1\nG1 Z0.4\nG1 X20 E1";
+    let gcode = Parsed::build(fine, true).expect("failed to parse");
+    assert_eq!(gcode.layers().len(), 3);
+    // a ramp within the layer, then the next layer
+    let ramp = "G28
+    G1 X10 Y10 Z0.2
+    G1 X20 E1
+    G1 X30 Z0.25 E1
+    G1 X40 Z0.3 E1
+    G1 Z0.4
+    G1 X10 E1";
+    let gcode = Parsed::build(ramp, true).expect("failed to parse");
+    let layers = gcode.layers();
+    assert_eq!(layers.len(), 2);
+    assert_eq!((layers[0].z, layers[1].z), (0.2, 0.4));
+    let marked = ";LAYER_CHANGE
+    ;Z:0.3
+    ;HEIGHT:0.3
+    G28
+    G1 X10 Y10 Z0.3
+    G1 X20 E1
+    ;LAYER_CHANGE
+    ;Z:0.5
+    ;HEIGHT:0.2
+    G1 Z0.5
+    G1 X10 E1
+    ;HEIGHT:0.1
+    G1 X20 E1";
+    let gcode = Parsed::build(marked, true).expect("failed to parse");
+    let layers = gcode.layers();
+    assert_eq!(layers.len(), 2);
+    assert_eq!((layers[1].z, layers[1].height), (0.5, 0.2));
+    assert_eq!(gcode.layer_of(&gcode.lines[5]).map(|l| l.index), Some(0));
+}
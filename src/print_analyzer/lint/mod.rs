@@ -0,0 +1,451 @@
+use super::machine::MachineProfile;
+use super::{Id, Instruction, Label, Parsed, Word};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub rule: &'static str,
+    pub severity: Severity,
+    pub message: String,
+    // offending lines, vertices or instructions
+    pub ids: Vec<Id>,
+}
+
+pub trait Rule: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic>;
+    // thresholds the rule can be tuned with, by name
+    fn params(&mut self) -> Vec<(&'static str, &mut f32)> {
+        Vec::new()
+    }
+}
+
+pub struct Linter {
+    // rules and whether they are enabled
+    pub rules: Vec<(Box<dyn Rule>, bool)>,
+}
+
+impl Default for Linter {
+    fn default() -> Self {
+        let rules: Vec<Box<dyn Rule>> = vec![
+            Box::new(ColdExtrusion),
+            Box::new(NegativeE { tolerance: 0.01 }),
+            Box::new(ZeroFeedrate),
+            Box::new(BuildVolume::default()),
+            Box::new(FanLeftOn),
+            Box::new(UnmatchedRetraction { tolerance: 0.01 }),
+            Box::new(AbnormalEPerMm { factor: 3.0 }),
+        ];
+        Linter {
+            rules: rules.into_iter().map(|r| (r, true)).collect(),
+        }
+    }
+}
+
+impl Linter {
+    pub fn run(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let mut out: Vec<Diagnostic> = self
+            .rules
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .flat_map(|(rule, _)| rule.check(gcode))
+            .collect();
+        // worst first, the sort is stable so each rule keeps its line order
+        out.sort_by_key(|d| std::cmp::Reverse(d.severity));
+        out
+    }
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) {
+        for (rule, e) in self.rules.iter_mut() {
+            if rule.name() == name {
+                *e = enabled;
+            }
+        }
+    }
+    // set a threshold of a rule by name, unknown rules and params are ignored
+    pub fn set_param(&mut self, rule: &str, param: &str, value: f32) {
+        for (r, _) in self.rules.iter_mut().filter(|(r, _)| r.name() == rule) {
+            for (name, v) in r.params() {
+                if name == param {
+                    *v = value;
+                }
+            }
+        }
+    }
+    // replace the rule with the same name, used to reconfigure a rule
+    pub fn replace(&mut self, new: Box<dyn Rule>) {
+        for (rule, _) in self.rules.iter_mut() {
+            if rule.name() == new.name() {
+                *rule = new;
+                return;
+            }
+        }
+        self.rules.push((new, true));
+    }
+}
+
+fn code(ins: &Instruction) -> Option<(char, i32)> {
+    match ins.first_word {
+        Word(letter, num, None) => Some((letter, num.round() as i32)),
+        _ => None,
+    }
+}
+
+fn param(ins: &Instruction, letter: char) -> Option<f32> {
+    ins.params
+        .as_ref()?
+        .iter()
+        .find(|Word(c, _, _)| *c == letter)
+        .map(|w| w.1)
+}
+
+// first word of a raw line like a klipper macro
+fn raw_command(ins: &Instruction) -> Option<String> {
+    match &ins.first_word {
+        Word(_, _, Some(raw)) => raw.split_whitespace().next().map(|s| s.to_uppercase()),
+        _ => None,
+    }
+}
+
+// extrusion before the hotend has been waited on
+pub struct ColdExtrusion;
+impl Rule for ColdExtrusion {
+    fn name(&self) -> &'static str {
+        "extrusion before heat-up"
+    }
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let mut ids = Vec::new();
+        for line in &gcode.lines {
+            if let Some(ins) = gcode.instructions.get(line) {
+                let wait = code(ins) == Some(('M', 109))
+                    || raw_command(ins).is_some_and(|c| {
+                        // start macros are expected to heat up before they return
+                        c == "TEMPERATURE_WAIT" || c == "PRINT_START" || c == "START_PRINT"
+                    });
+                if wait {
+                    break;
+                }
+            } else if let Some(v) = gcode.vertices.get(line) {
+                if v.to.e > 0.0 {
+                    ids.push(v.id);
+                }
+            }
+        }
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        vec![Diagnostic {
+            rule: self.name(),
+            severity: Severity::Error,
+            message: format!("{} extruding moves before waiting on the hotend", ids.len()),
+            ids,
+        }]
+    }
+}
+
+pub struct NegativeE {
+    // mm
+    pub tolerance: f32,
+}
+impl Rule for NegativeE {
+    fn name(&self) -> &'static str {
+        "negative cumulative e"
+    }
+    fn params(&mut self) -> Vec<(&'static str, &mut f32)> {
+        vec![("tolerance", &mut self.tolerance)]
+    }
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let mut e = 0.0;
+        let mut ids = Vec::new();
+        for line in &gcode.lines {
+            if let Some(v) = gcode.vertices.get(line) {
+                e += v.to.e;
+                if e < -self.tolerance {
+                    ids.push(v.id);
+                }
+            }
+        }
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        vec![Diagnostic {
+            rule: self.name(),
+            severity: Severity::Warning,
+            message: format!(
+                "{} moves with more filament retracted than extruded",
+                ids.len()
+            ),
+            ids,
+        }]
+    }
+}
+
+pub struct ZeroFeedrate;
+impl Rule for ZeroFeedrate {
+    fn name(&self) -> &'static str {
+        "zero feedrate"
+    }
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let ids: Vec<Id> = gcode
+            .lines
+            .iter()
+            .filter_map(|l| gcode.vertices.get(l))
+            .filter(|v| v.label != Label::Home && v.to.f == 0.0)
+            .map(|v| v.id)
+            .collect();
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        vec![Diagnostic {
+            rule: self.name(),
+            severity: Severity::Error,
+            message: format!("{} moves at F0", ids.len()),
+            ids,
+        }]
+    }
+}
+
+#[derive(Default)]
+pub struct BuildVolume {
+    pub profile: MachineProfile,
+}
+impl Rule for BuildVolume {
+    fn name(&self) -> &'static str {
+        "outside build volume"
+    }
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let mut ids: Vec<Id> = gcode
+            .check_bounds(&self.profile)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        ids.dedup();
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        vec![Diagnostic {
+            rule: self.name(),
+            severity: Severity::Error,
+            message: format!(
+                "{} moves outside the build volume of {}",
+                ids.len(),
+                self.profile.name
+            ),
+            ids,
+        }]
+    }
+}
+
+pub struct FanLeftOn;
+impl Rule for FanLeftOn {
+    fn name(&self) -> &'static str {
+        "fan left on"
+    }
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let mut last_on = None;
+        for line in &gcode.lines {
+            let Some(ins) = gcode.instructions.get(line) else {
+                continue;
+            };
+            match code(ins) {
+                Some(('M', 106)) if param(ins, 'S').unwrap_or(255.0) > 0.0 => last_on = Some(*line),
+                Some(('M', 106)) | Some(('M', 107)) => last_on = None,
+                _ => (),
+            }
+        }
+        let Some(id) = last_on else {
+            return Vec::new();
+        };
+        vec![Diagnostic {
+            rule: self.name(),
+            severity: Severity::Warning,
+            message: String::from("part cooling fan is never turned off at the end"),
+            ids: vec![id],
+        }]
+    }
+}
+
+pub struct UnmatchedRetraction {
+    // mm
+    pub tolerance: f32,
+}
+impl Rule for UnmatchedRetraction {
+    fn name(&self) -> &'static str {
+        "unmatched retraction"
+    }
+    fn params(&mut self) -> Vec<(&'static str, &mut f32)> {
+        vec![("tolerance", &mut self.tolerance)]
+    }
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let mut out = Vec::new();
+        // filament currently pulled back, negative if primed past where it started
+        let mut retracted: f32 = 0.0;
+        let mut fw_retracted = false;
+        // the retractions not primed back yet
+        let mut pending = Vec::new();
+        for line in &gcode.lines {
+            if let Some(ins) = gcode.instructions.get(line) {
+                match code(ins) {
+                    Some(('G', 10)) => {
+                        if fw_retracted {
+                            out.push((Severity::Warning, "G10 while already retracted", *line));
+                        }
+                        fw_retracted = true;
+                    }
+                    Some(('G', 11)) => {
+                        if !fw_retracted {
+                            out.push((Severity::Warning, "G11 without a G10", *line));
+                        }
+                        fw_retracted = false;
+                    }
+                    _ => (),
+                }
+                continue;
+            }
+            let Some(v) = gcode.vertices.get(line) else {
+                continue;
+            };
+            if v.extrusion_move() {
+                if retracted > self.tolerance || fw_retracted {
+                    out.push((Severity::Error, "extruding while retracted", v.id));
+                }
+                retracted = 0.0;
+                pending.clear();
+                continue;
+            }
+            if v.to.e < 0.0 {
+                retracted -= v.to.e;
+                pending.push(v.id);
+            } else if v.to.e > 0.0 {
+                retracted -= v.to.e;
+                if retracted < -self.tolerance {
+                    out.push((Severity::Warning, "deretraction without a retraction", v.id));
+                    retracted = 0.0;
+                }
+            }
+        }
+        if retracted > self.tolerance && !fw_retracted {
+            if let Some(id) = pending.first() {
+                out.push((Severity::Info, "still retracted at the end", *id));
+            }
+        }
+        out.into_iter()
+            .map(|(severity, message, id)| Diagnostic {
+                rule: self.name(),
+                severity,
+                message: message.to_string(),
+                ids: vec![id],
+            })
+            .collect()
+    }
+}
+
+pub struct AbnormalEPerMm {
+    // flag moves this many times over or under the median e per mm
+    pub factor: f32,
+}
+impl Rule for AbnormalEPerMm {
+    fn name(&self) -> &'static str {
+        "abnormal e per mm"
+    }
+    fn params(&mut self) -> Vec<(&'static str, &mut f32)> {
+        vec![("factor", &mut self.factor)]
+    }
+    fn check(&self, gcode: &Parsed) -> Vec<Diagnostic> {
+        let mut rates = Vec::new();
+        for line in &gcode.lines {
+            let Some(v) = gcode.vertices.get(line) else {
+                continue;
+            };
+            if !v.extrusion_move() {
+                continue;
+            }
+            let len = v.get_from(gcode).dist(&v.to);
+            if len > f32::EPSILON {
+                rates.push((v.id, v.to.e / len));
+            }
+        }
+        if rates.is_empty() {
+            return Vec::new();
+        }
+        let mut sorted: Vec<f32> = rates.iter().map(|(_, r)| *r).collect();
+        sorted.sort_by(f32::total_cmp);
+        let median = sorted[sorted.len() / 2];
+        let ids: Vec<Id> = rates
+            .into_iter()
+            .filter(|(_, r)| *r > median * self.factor || *r < median / self.factor)
+            .map(|(id, _)| id)
+            .collect();
+        if ids.is_empty() {
+            return Vec::new();
+        }
+        vec![Diagnostic {
+            rule: self.name(),
+            severity: Severity::Warning,
+            message: format!(
+                "{} moves more than {}x off the median {:.4} e/mm",
+                ids.len(),
+                self.factor,
+                median
+            ),
+            ids,
+        }]
+    }
+}
+
+#[test]
+fn lint_rules() {
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 E-1
+    G1 X20 E0.4
+    M109 S210
+    G1 X30 E0.4
+    G1 X31 E2
+    M106 S255
+    G1 X40 F0";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let diagnostics = Linter::default().run(&gcode);
+    let rules: Vec<&str> = diagnostics.iter().map(|d| d.rule).collect();
+    for rule in [
+        "extrusion before heat-up",
+        "negative cumulative e",
+        "zero feedrate",
+        "fan left on",
+        "unmatched retraction",
+        "abnormal e per mm",
+    ] {
+        assert!(rules.contains(&rule), "{} not reported", rule);
+    }
+    assert!(!rules.contains(&"outside build volume"));
+    // a retraction primed back in place before printing on is fine
+    let gcode = "G28
+    M109 S210
+    G1 X10 Y10 Z0.2 F1200
+    G1 X20 E1
+    G1 E-0.8
+    G1 X40 Y20
+    G1 E0.8
+    G1 X50 E1
+    G1 E-0.8";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let mut linter = Linter::default();
+    let unmatched = |linter: &Linter| -> Vec<Diagnostic> {
+        linter
+            .run(&gcode)
+            .into_iter()
+            .filter(|d| d.rule == "unmatched retraction")
+            .collect()
+    };
+    // only the retraction left at the end is reported
+    let diagnostics = unmatched(&linter);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].severity, Severity::Info);
+    linter.set_param("unmatched retraction", "tolerance", 1.0);
+    assert!(unmatched(&linter).is_empty());
+}
@@ -0,0 +1,295 @@
+use super::planner::{Cornering, MachineLimits};
+use super::{Id, Label, Parsed, Pos};
+use serde_json::Value;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BedShape {
+    // printable xy range
+    Rectangle { min: [f32; 2], max: [f32; 2] },
+    // delta beds
+    Circle { center: [f32; 2], radius: f32 },
+}
+
+// width of the strip along the front and left of the bed where purge lines go
+const PREPRINT_MARGIN: f32 = 5.0;
+
+impl BedShape {
+    // at least `inset` mm in from the edge
+    pub fn contains(&self, x: f32, y: f32, inset: f32) -> bool {
+        match self {
+            BedShape::Rectangle { min, max } => {
+                x >= min[0] + inset
+                    && x <= max[0] - inset
+                    && y >= min[1] + inset
+                    && y <= max[1] - inset
+            }
+            BedShape::Circle { center, radius } => {
+                let r = (radius - inset).max(0.0);
+                (x - center[0]).powi(2) + (y - center[1]).powi(2) <= r * r
+            }
+        }
+    }
+    // xy bounding box
+    pub fn bounds(&self) -> ([f32; 2], [f32; 2]) {
+        match self {
+            BedShape::Rectangle { min, max } => (*min, *max),
+            BedShape::Circle { center, radius } => (
+                [center[0] - radius, center[1] - radius],
+                [center[0] + radius, center[1] + radius],
+            ),
+        }
+    }
+}
+
+// area of the bed the nozzle must stay out of, like sheet clips or purge chutes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Zone {
+    pub name: String,
+    pub min: [f32; 2],
+    pub max: [f32; 2],
+    // the zone only applies to moves at or below this height
+    pub below_z: f32,
+}
+
+impl Zone {
+    pub fn contains(&self, x: f32, y: f32, z: f32) -> bool {
+        z <= self.below_z
+            && x >= self.min[0]
+            && x <= self.max[0]
+            && y >= self.min[1]
+            && y <= self.max[1]
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MachineProfile {
+    pub name: String,
+    pub bed: BedShape,
+    pub max_z: f32,
+    pub excluded: Vec<Zone>,
+    pub nozzle_diameter: f32,
+    // starting firmware limits before any limit commands in the file
+    pub limits: MachineLimits,
+}
+
+impl Default for MachineProfile {
+    fn default() -> Self {
+        MachineProfile {
+            name: String::from("generic"),
+            bed: BedShape::Rectangle {
+                min: [0.0, 0.0],
+                max: [250.0, 210.0],
+            },
+            max_z: 220.0,
+            excluded: Vec::new(),
+            nozzle_diameter: 0.4,
+            limits: MachineLimits::default(),
+        }
+    }
+}
+
+fn f32_of(value: &Value, key: &str) -> Option<f32> {
+    value.get(key)?.as_f64().map(|v| v as f32)
+}
+
+fn pair_of(value: &Value, key: &str) -> Option<[f32; 2]> {
+    let arr = value.get(key)?.as_array()?;
+    Some([arr.first()?.as_f64()? as f32, arr.get(1)?.as_f64()? as f32])
+}
+
+fn axes_of(value: &Value, key: &str, default: [f32; 4]) -> [f32; 4] {
+    let mut out = default;
+    if let Some(v) = value.get(key) {
+        for (i, axis) in ["x", "y", "z", "e"].iter().enumerate() {
+            if let Some(n) = f32_of(v, axis) {
+                out[i] = n;
+            }
+        }
+    }
+    out
+}
+
+impl MachineProfile {
+    // missing keys keep their default values
+    pub fn from_json(json: &str) -> Result<MachineProfile, Box<dyn std::error::Error>> {
+        let value: Value = serde_json::from_str(json)?;
+        let mut out = MachineProfile::default();
+        if let Some(name) = value.get("name").and_then(|v| v.as_str()) {
+            out.name = name.to_string();
+        }
+        if let Some(bed) = value.get("bed") {
+            // origin is where x0 y0 sits measured from the front left corner, like prusaslicer
+            let origin = pair_of(bed, "origin").unwrap_or([0.0, 0.0]);
+            out.bed = match bed.get("shape").and_then(|v| v.as_str()) {
+                Some("circle") => BedShape::Circle {
+                    center: pair_of(bed, "center").unwrap_or([0.0, 0.0]),
+                    radius: f32_of(bed, "diameter").ok_or("circular bed needs a diameter")? / 2.0,
+                },
+                Some("rectangle") | None => {
+                    let size = pair_of(bed, "size").ok_or("rectangular bed needs a size")?;
+                    BedShape::Rectangle {
+                        min: [-origin[0], -origin[1]],
+                        max: [size[0] - origin[0], size[1] - origin[1]],
+                    }
+                }
+                Some(shape) => return Err(format!("unknown bed shape {}", shape).into()),
+            };
+        }
+        if let Some(z) = f32_of(&value, "max z") {
+            out.max_z = z;
+        }
+        if let Some(d) = f32_of(&value, "nozzle diameter") {
+            out.nozzle_diameter = d;
+        }
+        if let Some(zones) = value.get("excluded zones").and_then(|v| v.as_array()) {
+            for zone in zones {
+                out.excluded.push(Zone {
+                    name: zone
+                        .get("name")
+                        .and_then(|v| v.as_str())
+                        .unwrap_or("excluded zone")
+                        .to_string(),
+                    min: pair_of(zone, "min").ok_or("excluded zone needs a min")?,
+                    max: pair_of(zone, "max").ok_or("excluded zone needs a max")?,
+                    below_z: f32_of(zone, "below z").unwrap_or(f32::INFINITY),
+                });
+            }
+        }
+        if let Some(limits) = value.get("limits") {
+            let l = &mut out.limits;
+            l.max_feedrate = axes_of(limits, "max feedrate", l.max_feedrate);
+            l.max_accel = axes_of(limits, "max accel", l.max_accel);
+            l.jerk = axes_of(limits, "jerk", l.jerk);
+            l.print_accel = f32_of(limits, "print accel").unwrap_or(l.print_accel);
+            l.retract_accel = f32_of(limits, "retract accel").unwrap_or(l.retract_accel);
+            l.travel_accel = f32_of(limits, "travel accel").unwrap_or(l.travel_accel);
+            l.max_velocity = f32_of(limits, "max velocity").unwrap_or(l.max_velocity);
+            if let Some(jd) = f32_of(limits, "junction deviation") {
+                l.cornering = Cornering::JunctionDeviation(jd);
+            }
+            if let Some(scv) = f32_of(limits, "square corner velocity") {
+                l.cornering = Cornering::SquareCorner(scv);
+            }
+        }
+        Ok(out)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutOfBounds {
+    OffBed,
+    BelowBed,
+    AboveMaxZ,
+    // name of the zone
+    InZone(String),
+}
+
+impl Parsed {
+    // label moves along the near edges of this machine's bed as pre-print moves
+    pub fn set_machine(&mut self, profile: &MachineProfile) {
+        let (min, _) = profile.bed.bounds();
+        self.preprint_edge = [min[0] + PREPRINT_MARGIN, min[1] + PREPRINT_MARGIN];
+        let mut changed = Vec::new();
+        let mut from = Pos::home();
+        for line in &self.lines {
+            let Some(v) = self.vertices.get_mut(line) else {
+                continue;
+            };
+            if v.label != Label::Home {
+                let old = v.label;
+                v.label(&from, self.preprint_edge);
+                if v.label != old {
+                    changed.push(v.id);
+                }
+            }
+            from = v.to;
+        }
+        if !changed.is_empty() {
+            self.reindex(&changed);
+            // shapes and layers were split on the old labels
+            self.assign_shapes();
+        }
+    }
+    // every move that ends outside the printable volume or inside an excluded zone
+    pub fn check_bounds(&self, profile: &MachineProfile) -> Vec<(Id, OutOfBounds)> {
+        let mut out = Vec::new();
+        for line in &self.lines {
+            let Some(v) = self.vertices.get(line) else {
+                continue;
+            };
+            if v.label == Label::Home {
+                continue;
+            }
+            let Pos { x, y, z, .. } = v.to;
+            // the bead is as wide as the nozzle at least
+            let inset = if v.extrusion_move() {
+                profile.nozzle_diameter / 2.0
+            } else {
+                0.0
+            };
+            if !profile.bed.contains(x, y, inset) {
+                out.push((v.id, OutOfBounds::OffBed));
+            }
+            if z < 0.0 {
+                out.push((v.id, OutOfBounds::BelowBed));
+            }
+            if z > profile.max_z {
+                out.push((v.id, OutOfBounds::AboveMaxZ));
+            }
+            for zone in &profile.excluded {
+                if zone.contains(x, y, z) {
+                    out.push((v.id, OutOfBounds::InZone(zone.name.clone())));
+                }
+            }
+        }
+        out
+    }
+}
+
+#[test]
+fn delta_profile() {
+    let json = r#"{
+        "name": "delta",
+        "bed": { "shape": "circle", "diameter": 200 },
+        "max z": 300,
+        "excluded zones": [{ "name": "clip", "min": [-5, 90], "max": [5, 100], "below z": 1 }]
+    }"#;
+    let profile = MachineProfile::from_json(json).expect("failed to parse profile");
+    let gcode = "G28\nG1 X0 Y95 Z0.2\nG1 X99 Y20\nG1 X0 Y0 Z301\n";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let out: Vec<OutOfBounds> = gcode
+        .check_bounds(&profile)
+        .into_iter()
+        .map(|o| o.1)
+        .collect();
+    assert_eq!(
+        out,
+        vec![
+            OutOfBounds::InZone("clip".to_string()),
+            OutOfBounds::OffBed,
+            OutOfBounds::AboveMaxZ
+        ]
+    );
+    // origin in the middle of the bed, x2 y2 is nowhere near its edge
+    let json = r#"{ "bed": { "size": [200, 200], "origin": [100, 100] } }"#;
+    let profile = MachineProfile::from_json(json).expect("failed to parse profile");
+    let gcode = "G28\nG1 X2 Y2 Z0.2\nG1 X99.9 E1\nG1 X-97 Y-97\n";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    assert_eq!(gcode.vertices[&gcode.lines[1]].label, Label::PrePrintMove);
+    assert!(gcode.layers.is_empty());
+    gcode.set_machine(&profile);
+    assert_eq!(gcode.layers.len(), 1);
+    assert_eq!(gcode.layer_of(&gcode.lines[2]).map(|l| l.z), Some(0.2));
+    let labels: Vec<Label> = gcode
+        .lines
+        .iter()
+        .map(|id| gcode.vertices[id].label)
+        .collect();
+    assert_eq!(
+        labels[1..],
+        [Label::LiftZ, Label::PlanarExtrustion, Label::PrePrintMove]
+    );
+    // the bead would hang off the edge
+    let out = gcode.check_bounds(&profile);
+    assert_eq!(out, vec![(gcode.lines[2], OutOfBounds::OffBed)]);
+}
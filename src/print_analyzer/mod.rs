@@ -1,7 +1,30 @@
+pub mod arc;
+pub mod bed_mesh;
+pub mod collision;
+pub mod compensate;
+pub mod contour;
 pub mod emit;
 mod file_reader;
+pub mod flow;
+pub mod layers;
+pub mod lint;
+pub mod machine;
+pub mod overhang;
+pub mod pause;
+pub mod planner;
+pub mod retract;
+pub mod seam;
+pub mod snippet;
+pub mod spatial;
+pub mod store;
+pub mod tower;
 mod transform;
+pub mod travel;
+pub mod tune;
+pub mod usage;
+pub mod warp;
 use std::collections::{HashMap, HashSet};
+use store::{Order, Slots};
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Id(u32);
@@ -37,24 +60,6 @@ impl Instruction {
             params: Some(line),
         }
     }
-    pub fn insert_temp_retraction(gcode: &mut Parsed) -> Id {
-        let id = gcode.id_counter.get();
-        let ins = Instruction {
-            first_word: Word('X', f32::NEG_INFINITY, Some(String::from("; retraction"))),
-            params: None,
-        };
-        assert!(gcode.instructions.insert(id, ins).is_none());
-        id
-    }
-    pub fn insert_temp_deretraction(gcode: &mut Parsed) -> Id {
-        let id = gcode.id_counter.get();
-        let ins = Instruction {
-            first_word: Word('X', f32::NEG_INFINITY, Some(String::from("; deretraction"))),
-            params: None,
-        };
-        assert!(gcode.instructions.insert(id, ins).is_none());
-        id
-    }
 }
 
 // intermediary struct for parsing line into vertex
@@ -146,10 +151,6 @@ pub struct Vertex {
     pub id: Id,
     pub count: u32,
     pub label: Label,
-    // this is the id of the previous extrusion move
-    pub prev: Option<Id>,
-    // this is the id of the next extrusion move
-    pub next: Option<Id>,
     pub to: Pos,
 }
 impl std::fmt::Debug for Vertex {
@@ -164,37 +165,34 @@ impl std::fmt::Debug for Vertex {
 impl Vertex {
     fn build(parsed: &mut Parsed, prev: &Id, g1: G1) -> Vertex {
         let id = parsed.id_counter.get();
-        let p = parsed.vertices.get_mut(prev).unwrap();
+        let p = parsed.vertices[prev];
         let mut vrtx = Vertex {
             id,
             count: p.count + 1,
             label: Label::Uninitialized,
             to: Pos::build(&p.to, &g1),
-            prev: Some(*prev),
-            next: p.next,
         };
-        p.next = Some(id);
-        vrtx.label(parsed);
+        vrtx.label(&p.to, parsed.preprint_edge);
         vrtx
     }
     pub fn get_from(&self, parsed: &Parsed) -> Pos {
-        if let Some(prev) = self.prev {
-            parsed.vertices.get(&prev).unwrap().to
+        if let Some(prev) = parsed.lines.prev(&self.id) {
+            parsed.vertices[&prev].to
         } else {
             Pos::home()
         }
     }
-    fn label(&mut self, parsed: &Parsed) {
-        let from = self.get_from(parsed);
+    // moves ending before `edge` in x or y are purge lines and the like along the bed edges
+    fn label(&mut self, from: &Pos, edge: [f32; 2]) {
         let dx = self.to.x - from.x;
         let dy = self.to.y - from.y;
         let dz = self.to.z - from.z;
         let de = self.to.e;
         self.label = {
-            if self.to.x < 5.0 || self.to.y < 5.0 {
+            if self.to.x < edge[0] || self.to.y < edge[1] {
                 Label::PrePrintMove
             } else if de > 0.0 {
-                if dx.abs() + dy.abs() > 0.0 - f32::EPSILON {
+                if dx.abs() + dy.abs() + dz.abs() > f32::EPSILON {
                     if dz.abs() > f32::EPSILON {
                         Label::NonPlanarExtrusion
                     } else {
@@ -253,24 +251,34 @@ impl Shape {
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Parsed {
-    pub lines: Vec<Id>, // keep track of line order
-    pub vertices: HashMap<Id, Vertex>,
-    pub instructions: HashMap<Id, Instruction>,
+    pub lines: Order, // keep track of line order
+    pub vertices: Slots<Vertex>,
+    pub instructions: Slots<Instruction>,
     pub shapes: Vec<Shape>,
+    // index into `shapes` of every line, rebuilt with them
+    shape_of: HashMap<Id, usize>,
+    layers: Vec<layers::Layer>,
     pub rel_xyz: bool,
     pub rel_e: bool,
     id_counter: Id,
+    index: spatial::SpatialIndex,
+    // see `Vertex::label`, set from the machine profile
+    preprint_edge: [f32; 2],
 }
 impl Parsed {
     pub fn new() -> Parsed {
         Parsed {
-            lines: Vec::new(),
-            vertices: HashMap::new(),
-            instructions: HashMap::new(),
+            lines: Order::default(),
+            vertices: Slots::default(),
+            instructions: Slots::default(),
             shapes: Vec::new(),
+            shape_of: HashMap::new(),
+            layers: Vec::new(),
             rel_xyz: false,
             rel_e: true,
             id_counter: Id(0),
+            index: spatial::SpatialIndex::default(),
+            preprint_edge: [5.0, 5.0],
         }
     }
     pub fn build(path: &str, testing: bool) -> Result<Parsed, Box<dyn std::error::Error>> {
@@ -308,19 +316,17 @@ impl Parsed {
                         count: 0,
                         label: Label::Home,
                         to: Pos::home(),
-                        prev: None,
-                        next: None,
                     };
                     assert!(parsed.vertices.insert(id, vrtx).is_none());
                     prev = Some(id);
-                    parsed.lines.push(id);
+                    parsed.lines.push(id, true);
                 }
                 ('G', 1) => {
                     // if prev is None, it means no homing command has been read
                     let p = prev.expect("g1 move from unhomed state");
                     let g1 = G1::build(line);
                     let vrtx = Vertex::build(&mut parsed, &p, g1);
-                    parsed.lines.push(vrtx.id);
+                    parsed.lines.push(vrtx.id, true);
                     prev = Some(vrtx.id);
                     assert!(parsed.vertices.insert(vrtx.id, vrtx).is_none());
                 }
@@ -341,12 +347,13 @@ impl Parsed {
                     line.push(word);
                     let id = parsed.id_counter.get();
                     let ins = Instruction::build(line);
-                    parsed.lines.push(id);
+                    parsed.lines.push(id, false);
                     assert!(parsed.instructions.insert(id, ins).is_none());
                 }
             }
         }
         parsed.assign_shapes();
+        parsed.rebuild_index();
         Ok(parsed)
     }
     pub fn centroid(&self) -> Vec3 {
@@ -366,6 +373,8 @@ impl Parsed {
         Vec3::new(x, y, z)
     }
     pub fn assign_shapes(&mut self) {
+        // shapes are numbered apart from lines, every layout starts over
+        let mut shape_ids = Id(0);
         let mut out = Vec::new();
         let mut temp_shape = Vec::new();
         let mut layer = -1.0;
@@ -376,7 +385,7 @@ impl Parsed {
                 }
                 if vertex.change_move() {
                     let shape = Shape {
-                        id: self.id_counter.get(),
+                        id: shape_ids.get(),
                         lines: temp_shape,
                         layer,
                     };
@@ -392,13 +401,18 @@ impl Parsed {
         }
         if !temp_shape.is_empty() {
             let shape = Shape {
-                id: self.id_counter.get(),
+                id: shape_ids.get(),
                 lines: temp_shape,
                 layer,
             };
             out.push(shape);
         }
+        self.shape_of.clear();
+        for (i, shape) in out.iter().enumerate() {
+            self.shape_of.extend(shape.lines.iter().map(|id| (*id, i)));
+        }
         self.shapes = out;
+        self.assign_layers();
     }
     pub fn get_centroid(&self, vertices: &HashSet<Id>) -> Vec3 {
         let (mut x, mut y, mut z, mut count) = (0.0, 0.0, 0.0, 0.0);
@@ -417,10 +431,55 @@ impl Parsed {
         let v = self.vertices.get(id).expect("vertex not found in map");
         let p = self
             .vertices
-            .get(&v.prev.unwrap())
+            .get(&self.lines.prev(id).unwrap())
             .expect("dist from vertex with no prev");
         p.to.dist(&v.to)
     }
+    // remove lines from the file, the moves on either side of them join up
+    pub fn remove_lines(&mut self, ids: &HashSet<Id>) {
+        let before: Vec<Id> = ids.iter().filter_map(|id| self.lines.prev(id)).collect();
+        for id in ids {
+            self.vertices.remove(id);
+            self.instructions.remove(id);
+            self.index.remove(id);
+        }
+        let vertices = &self.vertices;
+        self.lines.remove(ids, |id| vertices.contains_key(id));
+        self.reindex(&before);
+        self.set_counts();
+        self.assign_shapes();
+    }
+    // put lines back at the positions they will have afterwards
+    pub fn insert_lines_at(
+        &mut self,
+        lines: Vec<(usize, Id)>,
+        vertices: HashMap<Id, Vertex>,
+        instructions: HashMap<Id, Instruction>,
+    ) {
+        let ids: Vec<Id> = lines.iter().map(|(_, id)| *id).collect();
+        for (id, v) in vertices {
+            self.vertices.insert(id, v);
+        }
+        for (id, ins) in instructions {
+            self.instructions.insert(id, ins);
+        }
+        let vertices = &self.vertices;
+        self.lines.insert_at(lines, |id| vertices.contains_key(id));
+        self.reindex(&ids);
+        self.set_counts();
+        self.assign_shapes();
+    }
+    // swap ranges of positions for other lines, e.g. the same lines in a new order.
+    // new lines have to be in the maps already
+    fn replace_lines(&mut self, edits: Vec<(std::ops::Range<usize>, Vec<Id>)>) {
+        let ids: Vec<Id> = edits.iter().flat_map(|(_, ids)| ids).copied().collect();
+        let vertices = &self.vertices;
+        self.lines.replace(edits, |id| vertices.contains_key(id));
+        // reindexing a move also refreshes the one after it
+        self.reindex(&ids);
+        self.set_counts();
+        self.assign_shapes();
+    }
 
     pub fn hole_delete(&mut self, lines_to_delete: &mut HashSet<Id>) {
         for (id, v) in self.vertices.iter_mut() {
@@ -430,105 +489,38 @@ impl Parsed {
         }
     }
     pub fn merge_delete(&mut self, lines_to_delete: &mut HashSet<Id>) {
-        let mut temp = Vec::new();
-
-        for line in &self.lines {
-            if lines_to_delete.is_empty() {
-                break;
-            }
-            if lines_to_delete.contains(line) {
-                lines_to_delete.remove(line);
-                //  keep track of the prev node of the first vertex deleted in a block of verteces
-                let (_, vertex) = self
-                    .vertices
-                    .remove_entry(line)
-                    .expect("removing non-existent vertex");
-                if let Some(n) = vertex.next {
-                    let n = self.vertices.get_mut(&n).unwrap();
-                    n.prev = vertex.prev;
-                }
-                if let Some(p) = vertex.prev {
-                    let p = self.vertices.get_mut(&p).unwrap();
-                    p.next = vertex.next;
-                }
-            } else {
-                temp.push(line);
-            }
-        }
+        // only moves are merged away
+        lines_to_delete.retain(|id| self.vertices.contains_key(id));
+        self.remove_lines(lines_to_delete);
+        lines_to_delete.clear();
     }
 
-    pub fn translate(&mut self, id: &Id, dx: f32, dy: f32, dz: f32) {
-        let Some(v) = self.vertices.get(id) else {
-            return;
-        }; // in case a non-vertex instruction is searched, do nothing
-        if self.dist_from_prev(&v.id) < f32::EPSILON {
-            return; // dont translate moves without travel
-        }
-        let prev = v.prev.unwrap();
-        let init_dist = self.dist_from_prev(id);
-        let init_flow = self.vertices.get(id).unwrap().to.e;
-        let prev_dist = self.dist_from_prev(&prev);
-        {
-            let pv = self.vertices.get_mut(&prev).unwrap();
-            pv.to.x += dx;
-            pv.to.y += dy;
-            pv.to.z += dz;
-        }
-
-        let new_prev_dist = self.dist_from_prev(&prev);
-
-        let prev = self.vertices.get_mut(&prev).unwrap();
-
-        let mut scale = new_prev_dist / prev_dist;
-        if scale.is_infinite() || scale.is_nan() {
-            scale = 0.0;
-        }
-        prev.to.e *= scale;
-
-        let new_dist = self.dist_from_prev(id);
-        let mut scale = new_dist / init_dist;
-        if scale.is_infinite() || scale.is_nan() {
-            scale = 0.0;
-        }
-        let v = self.vertices.get_mut(id).unwrap();
-        v.to.e = init_flow * scale;
-    }
-    fn insert_lines_before(&mut self, mut lines: Vec<Id>, id: &Id) {
-        let mut i = 0;
-        for line in &self.lines {
-            if line == id {
-                break;
-            }
-            i += 1;
-        }
-        while let Some(line) = lines.pop() {
-            self.lines.insert(i, line);
-        }
-    }
-    fn subdivide_vertex(&mut self, id: &Id, count: u32) {
-        // FIXME: THIS IS DELETING MOVES
+    // insert each batch of new lines in front of the line it's keyed by
+    fn insert_lines_before(&mut self, batches: HashMap<Id, Vec<Id>>) {
+        let ids: Vec<Id> = batches.values().flatten().copied().collect();
+        let vertices = &self.vertices;
+        self.lines
+            .insert_before(batches, |id| vertices.contains_key(id));
+        self.reindex(&ids);
+    }
+    // split a move into `count` moves, returns the new vertices leading up to it.
+    // they still have to be put into the line order
+    fn subdivide_vertex(&mut self, id: &Id, count: u32) -> Vec<Id> {
         if count < 1 {
-            return;
+            return Vec::new();
         }
         // this is assuming relative e
         let v = self.vertices.get(id).unwrap();
         // don't subdivide moves with no extrustion
         if v.label != Label::PlanarExtrustion && v.label != Label::NonPlanarExtrusion {
-            return;
+            return Vec::new();
         }
-        let (xi, yi, zi) = {
-            if v.prev.is_none() {
-                (0.0, 0.0, 0.0)
-            } else {
-                let prev = self.vertices.get(&v.prev.unwrap()).unwrap();
-                (prev.to.x, prev.to.y, prev.to.z)
-            }
-        };
+        let from = v.get_from(self);
+        let (xi, yi, zi) = (from.x, from.y, from.z);
         let (xf, yf, zf, ef, f) = (v.to.x, v.to.y, v.to.z, v.to.e, v.to.f);
         let countf = count as f32;
         let (step_x, step_y, step_z) = ((xf - xi) / countf, (yf - yi) / countf, (zf - zi) / countf);
-        let mut prev = v.prev;
-        let mut vec = Vec::new();
+        let mut prev = from;
         let mut new_ids = Vec::new();
         for i in 1..count {
             let i = i as f32;
@@ -536,7 +528,6 @@ impl Parsed {
                 id: self.id_counter.get(),
                 count: 0, // this then needs to be counted and set
                 label: Label::Uninitialized,
-                prev,
                 to: Pos {
                     x: xi + (step_x * i),
                     y: yi + (step_y * i),
@@ -544,56 +535,52 @@ impl Parsed {
                     e: ef / countf,
                     f,
                 },
-                next: None, // this gets set as part of set_counts
             };
-            new.label(self);
+            new.label(&prev, self.preprint_edge);
+            prev = new.to;
             self.vertices.insert(new.id, new);
-            prev = Some(new.id);
             new_ids.push(new.id);
-            vec.push(new);
-        }
-        for id in &new_ids {
-            prev = Some(*id);
         }
-        self.insert_lines_before(new_ids, id);
         let v = self.vertices.get_mut(id).unwrap();
         v.to.e = ef / countf;
-        v.prev = prev;
+        new_ids
     }
     pub fn subdivide_vertices(&mut self, vertices: HashSet<Id>, count: u32) {
+        let mut batches = HashMap::new();
         for id in vertices {
-            self.subdivide_vertex(&id, count);
+            batches.insert(id, self.subdivide_vertex(&id, count));
         }
+        self.insert_lines_before(batches);
         self.set_counts();
+        self.assign_shapes();
     }
     pub fn subdivide_all(&mut self, max_dist: f32) {
-        let vertices = self.vertices.clone();
-        for id in vertices.keys() {
-            if self.vertices.contains_key(&id) {
-                let dist = self.dist_from_prev(&id);
+        let ids: Vec<Id> = self.vertices.keys().copied().collect();
+        self.subdivide_to(&ids, max_dist);
+        self.assign_shapes();
+    }
+    // split the moves to the vertices into moves of about `max_dist`, returns the new
+    // vertices. shapes are left for the caller to lay out once it's done
+    fn subdivide_to(&mut self, ids: &[Id], max_dist: f32) -> Vec<Id> {
+        let mut batches = HashMap::new();
+        for id in ids {
+            if self.vertices.contains_key(id) && self.lines.prev(id).is_some() {
+                let dist = self.dist_from_prev(id);
                 let count = (dist / max_dist).round() as u32;
-                self.subdivide_vertex(&id, count);
+                batches.insert(*id, self.subdivide_vertex(id, count));
             }
         }
+        let new = batches.values().flatten().copied().collect();
+        self.insert_lines_before(batches);
+        self.set_counts();
+        new
     }
 
     pub fn get_shape(&self, vertex: &Id) -> Vec<Id> {
-        for shape in self.shapes.iter() {
-            if shape.lines.contains(vertex) {
-                return shape.lines.clone();
-            }
-        }
-        Vec::new()
-    }
-    pub fn get_same_z(&self, vertex: &Id) -> Vec<Id> {
-        let mut out = Vec::new();
-        let z = self.vertices.get(vertex).unwrap().to.z;
-        for (_, vertex) in self.vertices.iter() {
-            if (vertex.to.z - z).abs() < f32::EPSILON {
-                out.push(vertex.id);
-            }
-        }
-        out
+        self.shape_of
+            .get(vertex)
+            .map(|i| self.shapes[*i].lines.clone())
+            .unwrap_or_default()
     }
     pub fn write_to_file(&self, path: &str) -> Result<(), std::io::Error> {
         use std::fs::File;
@@ -605,19 +592,16 @@ impl Parsed {
     }
     fn set_counts(&mut self) {
         let mut count = 0;
-        let mut next = None;
         for line in &self.lines {
             if let Some(v) = self.vertices.get_mut(line) {
                 v.count = count;
-                v.next = next;
-                next = Some(v.id);
                 count += 1;
             }
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Label {
     Uninitialized,
     Home,
@@ -639,11 +623,8 @@ pub enum Label {
 fn tran_test() {
     let test = "G28\ng1x1e1\ng1x2e1\ng1x3e1\n";
     let mut gcode = read(test, true).expect("failed to parse");
-    for line in gcode.lines.clone() {
-        if gcode.vertices.contains_key(&line) {
-            gcode.translate(&line, 0.0, 1.0, 0.0);
-        }
-    }
+    let lines: HashSet<Id> = gcode.lines.iter().copied().collect();
+    gcode.translate(&lines, Vec3::Y);
 }
 
 #[test]
@@ -680,11 +661,6 @@ fn _vertex_filter(gcode: &Parsed, f: fn(&Vertex) -> bool) -> HashSet<Id> {
     out
 }
 
-// fn insert_before(feature)
-// fn modify(feature)
-// fn replace_with(feature, gcode_sequence)
-// fn insert_after(feature)
-
 #[cfg(test)]
 use std::fs::File;
 use std::io::Write;
@@ -0,0 +1,128 @@
+use super::flow::Bead;
+use super::spatial::Segment;
+use super::{Id, Parsed};
+use bevy::math::Vec2;
+use std::collections::HashMap;
+
+// overhang angles in degrees from vertical where a new bucket starts
+pub const ANGLES: [f32; 3] = [35.0, 50.0, 60.0];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Support {
+    Supported,
+    // index of the highest angle in the thresholds that was reached
+    Overhang(usize),
+    // nothing printed under the bead at all
+    Bridge,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Overhang {
+    // fraction of the bead resting on the layer below
+    pub supported: f32,
+    // steepest point of the bead, degrees from vertical
+    pub angle: f32,
+    pub class: Support,
+}
+
+impl Parsed {
+    // how much of every extrusion is carried by the layer printed below it.
+    // `angles` are the overhang bucket thresholds in ascending order, e.g. `ANGLES`
+    pub fn overhangs(&self, beads: &HashMap<Id, Bead>, angles: &[f32]) -> HashMap<Id, Overhang> {
+        let zs = self.extrusion_zs();
+        let widest = beads.values().map(|b| b.width).fold(0.0, f32::max);
+        let mut out = HashMap::new();
+        for (id, bead) in beads {
+            let Some((from, to)) = self.index.segment(id) else {
+                continue;
+            };
+            let seg = Segment {
+                a: from.truncate(),
+                b: to.truncate(),
+                half_width: bead.width / 2.0,
+            };
+            let layer = zs.partition_point(|z| *z < to.z - 1e-4);
+            if layer == 0 {
+                let overhang = Overhang {
+                    supported: 1.0,
+                    angle: 0.0,
+                    class: Support::Supported,
+                };
+                out.insert(*id, overhang);
+                continue;
+            }
+            // beads of the layer below close enough to carry part of this one
+            let below: Vec<Segment> = self
+                .index
+                .near_segment(zs[layer - 1], seg.a, seg.b, seg.half_width + widest / 2.0)
+                .into_iter()
+                .filter_map(|id| {
+                    let (from, to) = self.index.segment(&id)?;
+                    Some(Segment {
+                        a: from.truncate(),
+                        b: to.truncate(),
+                        half_width: beads.get(&id)?.width / 2.0,
+                    })
+                })
+                .collect();
+            // signed distance to the edge of the nearest bead below, negative inside it
+            let edge_dist = |p: Vec2| {
+                below
+                    .iter()
+                    .map(|s| s.dist(p) - s.half_width)
+                    .fold(f32::INFINITY, f32::min)
+            };
+            let samples = (bead.length / seg.half_width).ceil().max(1.0) as usize;
+            let mut supported = 0.0;
+            let mut steepest: f32 = 0.0;
+            for i in 0..samples {
+                let p = seg.a.lerp(seg.b, (i as f32 + 0.5) / samples as f32);
+                // width of the bead hanging past the edge of the one below
+                let outward = (edge_dist(p) + seg.half_width).clamp(0.0, bead.width);
+                supported += 1.0 - outward / bead.width;
+                steepest = steepest.max(outward);
+            }
+            let supported = supported / samples as f32;
+            let angle = steepest.atan2(bead.height).to_degrees();
+            let class = if supported < 0.05 {
+                Support::Bridge
+            } else {
+                match angles.iter().rposition(|a| angle >= *a) {
+                    Some(bucket) => Support::Overhang(bucket),
+                    None => Support::Supported,
+                }
+            };
+            let overhang = Overhang {
+                supported,
+                angle,
+                class,
+            };
+            out.insert(*id, overhang);
+        }
+        out
+    }
+}
+
+#[test]
+fn stacked_shifted_and_bridged() {
+    use super::usage::Filament;
+    // a line, then on the next layer one bead on top of it, one shifted half a bead out
+    // and one out in the air
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    G1 Z0.4
+    G1 X10 E1
+    G1 Y10.25
+    G1 X30 E1
+    G1 Y20
+    G1 X10 E1";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let beads = gcode.beads(&Filament::default());
+    let overhangs = gcode.overhangs(&beads, &ANGLES);
+    let class = |line: usize| overhangs[&gcode.lines[line]].class;
+    assert_eq!(class(2), Support::Supported);
+    assert_eq!(class(4), Support::Supported);
+    assert!(matches!(class(6), Support::Overhang(_)));
+    assert_eq!(class(8), Support::Bridge);
+}
@@ -0,0 +1,112 @@
+use super::Parsed;
+use bevy::math::Vec2;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PauseKind {
+    // marlin filament change, also what most firmware does a color change with
+    M600,
+    // klipper's pause macro
+    Pause,
+    // prusa firmware pause
+    M601,
+}
+
+impl PauseKind {
+    fn command(&self) -> &'static str {
+        match self {
+            PauseKind::M600 => "M600",
+            PauseKind::Pause => "PAUSE",
+            PauseKind::M601 => "M601",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Pause {
+    pub kind: PauseKind,
+    // where to wait, or in place
+    pub park: Option<Vec2>,
+    // mm to raise z by before parking
+    pub lift: f32,
+    // mm of filament pulled back while paused
+    pub retract: f32,
+}
+
+impl Parsed {
+    // pause at the start of each layer range, see `features`. the nozzle retracts, lifts
+    // and parks, then comes back to exactly where the next move starts from
+    pub fn insert_pauses(
+        &mut self,
+        layers: &[Range<usize>],
+        pause: &Pause,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut starts: Vec<usize> = layers.iter().map(|r| r.start).collect();
+        starts.sort_unstable();
+        starts.dedup();
+        // last first, so the positions of the earlier ones hold
+        for start in starts.into_iter().rev() {
+            let Some(at) = self.pos_before(start) else {
+                continue;
+            };
+            let mut out = Vec::new();
+            if pause.retract > 0.0 {
+                out.push(format!("G1 E{} F2400", -pause.retract));
+            }
+            if pause.lift > 0.0 {
+                out.push(format!("G1 Z{} F600", at.z + pause.lift));
+            }
+            if let Some(park) = pause.park {
+                out.push(format!("G1 X{} Y{} F6000", park.x, park.y));
+            }
+            out.push(pause.kind.command().to_string());
+            if pause.park.is_some() {
+                out.push(format!("G1 X{} Y{} F6000", at.x, at.y));
+            }
+            if pause.lift > 0.0 {
+                out.push(format!("G1 Z{} F600", at.z));
+            }
+            if pause.retract > 0.0 {
+                out.push(format!("G1 E{} F2400", pause.retract));
+            }
+            self.splice(vec![(start..start, start)], &out.join("\n"))?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn park_between_layers() {
+    use super::emit::Emit;
+    use super::snippet::Feature;
+    use super::Id;
+    use std::collections::HashSet;
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    ;LAYER_CHANGE
+    G1 Z0.4
+    G1 X10 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let second: HashSet<Id> = [gcode.lines[5]].into_iter().collect();
+    let layers = gcode.features(&second, Feature::Layer);
+    let pause = Pause {
+        kind: PauseKind::Pause,
+        park: Some(Vec2::new(0.0, 200.0)),
+        lift: 5.0,
+        retract: 1.0,
+    };
+    gcode
+        .insert_pauses(&layers, &pause)
+        .expect("failed to pause");
+    let out = gcode.emit(&gcode, false);
+    let lines: Vec<&str> = out.lines().map(str::trim).collect();
+    let at = lines.iter().position(|l| *l == "PAUSE").expect("no pause");
+    assert_eq!(lines[at - 3], "G1 E-1 F2400");
+    assert_eq!(lines[at - 2], "G1 Z5.2 F600");
+    assert_eq!(lines[at + 1], "G1 X30 Y10");
+    assert_eq!(lines[at + 2], "G1 Z0.2 F600");
+    assert_eq!(lines[at + 3], "G1 E1 F2400");
+    assert_eq!(lines[at + 4], ";LAYER_CHANGE");
+    assert_eq!(lines[at + 5], "G1 Z0.4 F1200");
+}
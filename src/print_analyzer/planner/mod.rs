@@ -0,0 +1,421 @@
+use super::{Id, Instruction, Label, Parsed, Word};
+use std::collections::HashMap;
+
+// indexes into the per axis limit arrays
+const X: usize = 0;
+const Y: usize = 1;
+const Z: usize = 2;
+const E: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Cornering {
+    // marlin classic jerk, max instantaneous speed change per axis in mm/s
+    Jerk,
+    // marlin junction deviation in mm
+    JunctionDeviation(f32),
+    // klipper square corner velocity in mm/s
+    SquareCorner(f32),
+}
+
+// firmware motion limits, the defaults are overwritten by any
+// M201/M203/M204/M205/SET_VELOCITY_LIMIT found in the file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MachineLimits {
+    // mm/s for x, y, z, e
+    pub max_feedrate: [f32; 4],
+    // mm/s^2 for x, y, z, e
+    pub max_accel: [f32; 4],
+    pub print_accel: f32,
+    pub retract_accel: f32,
+    pub travel_accel: f32,
+    // mm/s for x, y, z, e
+    pub jerk: [f32; 4],
+    // klipper VELOCITY, caps every move
+    pub max_velocity: f32,
+    pub cornering: Cornering,
+}
+
+impl Default for MachineLimits {
+    fn default() -> Self {
+        MachineLimits {
+            max_feedrate: [500.0, 500.0, 12.0, 120.0],
+            max_accel: [3000.0, 3000.0, 100.0, 10000.0],
+            print_accel: 1500.0,
+            retract_accel: 1500.0,
+            travel_accel: 3000.0,
+            jerk: [10.0, 10.0, 0.3, 5.0],
+            max_velocity: f32::INFINITY,
+            cornering: Cornering::Jerk,
+        }
+    }
+}
+
+impl MachineLimits {
+    // update limits from a firmware command, returns true if the command was a limit setting
+    pub fn apply(&mut self, ins: &Instruction) -> bool {
+        let Instruction {
+            first_word: Word(letter, num, raw),
+            params,
+        } = ins;
+        if let Some(raw) = raw {
+            return self.apply_klipper(raw);
+        }
+        let params = params.as_deref().unwrap_or(&[]);
+        let axis = |c: char| match c {
+            'X' => Some(X),
+            'Y' => Some(Y),
+            'Z' => Some(Z),
+            'E' => Some(E),
+            _ => None,
+        };
+        match (letter, num.round() as i32) {
+            ('M', 201) => {
+                for Word(c, val, _) in params {
+                    if let Some(i) = axis(*c) {
+                        self.max_accel[i] = *val;
+                    }
+                }
+            }
+            ('M', 203) => {
+                for Word(c, val, _) in params {
+                    if let Some(i) = axis(*c) {
+                        self.max_feedrate[i] = *val;
+                    }
+                }
+            }
+            ('M', 204) => {
+                for Word(c, val, _) in params {
+                    match c {
+                        'S' => {
+                            self.print_accel = *val;
+                            self.travel_accel = *val;
+                        }
+                        'P' => self.print_accel = *val,
+                        'R' => self.retract_accel = *val,
+                        'T' => self.travel_accel = *val,
+                        _ => (),
+                    }
+                }
+            }
+            ('M', 205) => {
+                for Word(c, val, _) in params {
+                    if let Some(i) = axis(*c) {
+                        self.jerk[i] = *val;
+                    } else if *c == 'J' {
+                        self.cornering = Cornering::JunctionDeviation(*val);
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+    fn apply_klipper(&mut self, line: &str) -> bool {
+        let mut words = line.split_whitespace();
+        if !words
+            .next()
+            .is_some_and(|w| w.eq_ignore_ascii_case("SET_VELOCITY_LIMIT"))
+        {
+            return false;
+        }
+        for word in words {
+            let Some((key, val)) = word.split_once('=') else {
+                continue;
+            };
+            let Ok(val) = val.parse::<f32>() else {
+                continue;
+            };
+            match key.to_uppercase().as_str() {
+                "VELOCITY" => self.max_velocity = val,
+                "ACCEL" => {
+                    self.print_accel = val;
+                    self.travel_accel = val;
+                }
+                "SQUARE_CORNER_VELOCITY" => self.cornering = Cornering::SquareCorner(val),
+                _ => (),
+            }
+        }
+        true
+    }
+}
+
+// G4 dwell in seconds, None if the instruction is not a dwell
+fn dwell(ins: &Instruction) -> Option<f32> {
+    let Word(letter, num, None) = ins.first_word else {
+        return None;
+    };
+    if letter != 'G' || num.round() as i32 != 4 {
+        return None;
+    }
+    let mut out = 0.0;
+    for Word(c, val, _) in ins.params.as_deref().unwrap_or(&[]) {
+        match c {
+            'P' => out += val / 1000.0,
+            'S' => out += val,
+            _ => (),
+        }
+    }
+    Some(out)
+}
+
+// a single planned move
+struct Block {
+    id: Id,
+    len: f32,
+    // unit direction in xyz, zero for e only moves
+    dir: [f32; 3],
+    // e speed per unit of path speed
+    e_rate: f32,
+    nominal: f32,
+    accel: f32,
+    // max entry speed allowed by the junction with the previous block
+    max_entry: f32,
+    entry: f32,
+    // time spent stopped before this block starts (dwells)
+    dwell: f32,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimeEstimate {
+    pub total: f32,
+    // time since print start at the end of each vertex move
+    pub vertex_times: HashMap<Id, f32>,
+    // (layer z, seconds) in print order
+    pub layers: Vec<(f32, f32)>,
+    // shape id -> seconds
+    pub shapes: HashMap<Id, f32>,
+}
+
+impl TimeEstimate {
+    // seconds spent on a single vertex move
+    pub fn duration(&self, gcode: &Parsed, id: &Id) -> f32 {
+        let Some(end) = self.vertex_times.get(id) else {
+            return 0.0;
+        };
+        let start = gcode
+            .lines
+            .prev(id)
+            .and_then(|p| self.vertex_times.get(&p))
+            .unwrap_or(&0.0);
+        end - start
+    }
+}
+
+pub fn format_duration(seconds: f32) -> String {
+    let s = seconds.max(0.0).round() as u32;
+    format!("{}h {:02}m {:02}s", s / 3600, (s / 60) % 60, s % 60)
+}
+
+impl Parsed {
+    pub fn estimate_time(&self, defaults: &MachineLimits) -> TimeEstimate {
+        let mut limits = *defaults;
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut pending_dwell = 0.0;
+        for line in &self.lines {
+            if let Some(ins) = self.instructions.get(line) {
+                if let Some(t) = dwell(ins) {
+                    // the planner empties before a dwell, so the next block starts from rest
+                    pending_dwell += t;
+                } else {
+                    limits.apply(ins);
+                }
+                continue;
+            }
+            let Some(v) = self.vertices.get(line) else {
+                continue;
+            };
+            if v.label == Label::Home {
+                continue;
+            }
+            let from = v.get_from(self);
+            let (dx, dy, dz) = v.to - from;
+            let de = v.to.e;
+            let xyz = (dx * dx + dy * dy + dz * dz).sqrt();
+            let (len, dir, e_rate) = if xyz > f32::EPSILON {
+                (xyz, [dx / xyz, dy / xyz, dz / xyz], de / xyz)
+            } else if de.abs() > f32::EPSILON {
+                (de.abs(), [0.0; 3], de.signum())
+            } else {
+                continue; // feedrate only or empty moves take no time
+            };
+            let mut nominal = (v.to.f / 60.0).min(limits.max_velocity);
+            let mut accel = if xyz <= f32::EPSILON {
+                limits.retract_accel
+            } else if de > 0.0 {
+                limits.print_accel
+            } else {
+                limits.travel_accel
+            };
+            // scale down so no single axis goes over its limits
+            for (axis, c) in [dir[X], dir[Y], dir[Z], e_rate].iter().enumerate() {
+                let c = c.abs();
+                if c > f32::EPSILON {
+                    nominal = nominal.min(limits.max_feedrate[axis] / c);
+                    accel = accel.min(limits.max_accel[axis] / c);
+                }
+            }
+            if !nominal.is_finite() || nominal <= 0.0 {
+                nominal = limits.max_feedrate[X];
+            }
+            let mut block = Block {
+                id: v.id,
+                len,
+                dir,
+                e_rate,
+                nominal,
+                accel,
+                max_entry: 0.0,
+                entry: 0.0,
+                dwell: 0.0,
+            };
+            if pending_dwell == 0.0 {
+                if let Some(prev) = blocks.last() {
+                    block.max_entry = junction_speed(prev, &block, &limits);
+                }
+            }
+            block.dwell = pending_dwell;
+            pending_dwell = 0.0;
+            blocks.push(block);
+        }
+        plan(&mut blocks);
+
+        let mut out = TimeEstimate::default();
+        let mut clock = 0.0;
+        for (i, b) in blocks.iter().enumerate() {
+            let exit = blocks.get(i + 1).map_or(0.0, |n| n.entry);
+            clock += b.dwell + trapezoid_time(b.entry, exit, b.nominal, b.accel, b.len);
+            out.vertex_times.insert(b.id, clock);
+        }
+        // dwells after the last move
+        clock += pending_dwell;
+        out.total = clock;
+        self.total_by_shape(&mut out);
+        out
+    }
+    fn total_by_shape(&self, out: &mut TimeEstimate) {
+        // change moves aren't part of a shape, so attribute them to the following shape
+        let mut last = 0.0;
+        for shape in &self.shapes {
+            let end = shape
+                .lines
+                .iter()
+                .rev()
+                .find_map(|id| out.vertex_times.get(id))
+                .copied();
+            let Some(end) = end else {
+                continue;
+            };
+            let t = end - last;
+            last = end;
+            out.shapes.insert(shape.id, t);
+        }
+        let mut last = 0.0;
+        for layer in self.layers() {
+            let end = self.lines[layer.lines.clone()]
+                .iter()
+                .rev()
+                .find_map(|id| out.vertex_times.get(id))
+                .copied()
+                .unwrap_or(last);
+            out.layers.push((layer.z, end - last));
+            last = end;
+        }
+    }
+}
+
+fn junction_speed(prev: &Block, next: &Block, limits: &MachineLimits) -> f32 {
+    let cap = prev.nominal.min(next.nominal);
+    if prev.dir == [0.0; 3] || next.dir == [0.0; 3] {
+        return 0.0; // retractions start and end at rest
+    }
+    match limits.cornering {
+        Cornering::Jerk => {
+            // largest speed where no axis changes speed by more than its jerk
+            let mut v = cap;
+            let prev_v = [prev.dir[X], prev.dir[Y], prev.dir[Z], prev.e_rate];
+            let next_v = [next.dir[X], next.dir[Y], next.dir[Z], next.e_rate];
+            for axis in [X, Y, Z, E] {
+                let delta = (prev_v[axis] - next_v[axis]).abs();
+                if delta > f32::EPSILON {
+                    v = v.min(limits.jerk[axis] / delta);
+                }
+            }
+            v
+        }
+        Cornering::JunctionDeviation(jd) => deviation_speed(prev, next, jd, next.accel, cap),
+        Cornering::SquareCorner(scv) => {
+            // klipper derives its junction deviation from the square corner velocity
+            let jd = scv * scv * (std::f32::consts::SQRT_2 - 1.0) / next.accel;
+            deviation_speed(prev, next, jd, next.accel, cap)
+        }
+    }
+}
+
+fn deviation_speed(prev: &Block, next: &Block, jd: f32, accel: f32, cap: f32) -> f32 {
+    let cos_theta = -(0..3).map(|i| prev.dir[i] * next.dir[i]).sum::<f32>();
+    if cos_theta > 0.999999 {
+        return 0.0; // full reversal
+    }
+    if cos_theta < -0.999999 {
+        return cap; // straight line
+    }
+    let sin_half = (0.5 * (1.0 - cos_theta)).sqrt();
+    (accel * jd * sin_half / (1.0 - sin_half)).sqrt().min(cap)
+}
+
+// forward and reverse passes over the block entry speeds
+fn plan(blocks: &mut [Block]) {
+    for b in blocks.iter_mut() {
+        b.entry = b.max_entry;
+    }
+    let mut next_entry = 0.0;
+    for b in blocks.iter_mut().rev() {
+        b.entry = b
+            .entry
+            .min((next_entry * next_entry + 2.0 * b.accel * b.len).sqrt());
+        next_entry = b.entry;
+    }
+    let mut exit: f32 = 0.0;
+    for b in blocks.iter_mut() {
+        b.entry = b.entry.min(exit);
+        exit = (b.entry * b.entry + 2.0 * b.accel * b.len)
+            .sqrt()
+            .min(b.nominal);
+    }
+}
+
+fn trapezoid_time(v0: f32, v1: f32, vn: f32, a: f32, len: f32) -> f32 {
+    let vn = vn.max(v0).max(v1);
+    let accel_dist = (vn * vn - v0 * v0) / (2.0 * a);
+    let decel_dist = (vn * vn - v1 * v1) / (2.0 * a);
+    if accel_dist + decel_dist > len {
+        // triangle profile, never reaches nominal speed
+        let peak = ((2.0 * a * len + v0 * v0 + v1 * v1) / 2.0).sqrt();
+        return (peak - v0) / a + (peak - v1) / a;
+    }
+    (vn - v0) / a + (vn - v1) / a + (len - accel_dist - decel_dist) / vn
+}
+
+#[test]
+fn straight_line_time() {
+    let gcode = "G28\nM204 S1000\nG1 X10 Y10 Z0.2 F6000\nG1 E1\nG1 X110 E5\nG4 P500\n";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let est = gcode.estimate_time(&MachineLimits::default());
+    // 100mm at 100mm/s with 1000mm/s^2 accel: 0.1s up, 0.1s down, 90mm cruise in 0.9s
+    let id = gcode.lines[gcode.lines.len() - 2];
+    assert!((est.duration(&gcode, &id) - 1.1).abs() < 1e-3);
+    assert!(est.total > est.vertex_times[&id] + 0.5 - 1e-3);
+}
+
+#[test]
+fn klipper_limits() {
+    let gcode =
+        "G28\nSET_VELOCITY_LIMIT VELOCITY=50 ACCEL=500 SQUARE_CORNER_VELOCITY=5\nG1 X100 F9000\n";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let mut limits = MachineLimits::default();
+    for ins in gcode.instructions.values() {
+        limits.apply(ins);
+    }
+    assert_eq!(limits.max_velocity, 50.0);
+    assert_eq!(limits.cornering, Cornering::SquareCorner(5.0));
+}
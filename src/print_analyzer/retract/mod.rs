@@ -0,0 +1,248 @@
+use super::{Id, Instruction, Label, Parsed, Pos, Vertex, Word};
+use core::f32::consts::TAU;
+use std::collections::HashSet;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ZHop {
+    Off,
+    // straight up before the travel
+    Straight,
+    // climbing over the first travel move
+    Ramp,
+    // a helix up from where the nozzle stopped
+    Spiral,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Retract {
+    // mm of filament
+    pub length: f32,
+    // mm/min pulling back and pushing back in
+    pub speed: f32,
+    pub prime_speed: f32,
+    // G10 and G11, the firmware's own settings are used
+    pub firmware: bool,
+    pub hop: ZHop,
+    pub hop_height: f32,
+    pub hop_speed: f32,
+    // without a selection, travels at least this long in mm are retracted
+    pub min_travel: f32,
+}
+
+// spirals are this wide, in mm, and go round once in this many moves
+const SPIRAL_RADIUS: f32 = 1.0;
+const SPIRAL_STEPS: usize = 8;
+
+impl Parsed {
+    // retract across every selected travel, or every long enough one if nothing is selected.
+    // travels that already retract are left alone. returns how many were changed
+    pub fn insert_retractions(&mut self, selection: &HashSet<Id>, retract: &Retract) -> usize {
+        let mut edits = Vec::new();
+        let mut changed = Vec::new();
+        for run in self.travel_runs() {
+            if !self.needs_retraction(run.clone(), selection, retract) {
+                continue;
+            }
+            let lines = self.retract_run(run.clone(), retract);
+            changed.extend(&lines);
+            // the move that carries on printing
+            changed.extend(self.lines.get(run.end));
+            edits.push((run, lines));
+        }
+        let count = edits.len();
+        if count > 0 {
+            self.replace_lines(edits);
+            for id in &changed {
+                if let Some(v) = self.vertices.get(id) {
+                    let from = v.get_from(self);
+                    self.vertices
+                        .get_mut(id)
+                        .unwrap()
+                        .label(&from, self.preprint_edge);
+                }
+            }
+            self.reindex(&changed);
+        }
+        count
+    }
+    // positions of the lines between one extrusion and the next
+    fn travel_runs(&self) -> Vec<Range<usize>> {
+        let mut out = Vec::new();
+        let mut start = None;
+        for (i, id) in self.lines.iter().enumerate() {
+            if self.vertices.get(id).is_some_and(|v| v.extrusion_move()) {
+                if let Some(start) = start.filter(|s| *s < i) {
+                    out.push(start..i);
+                }
+                start = Some(i + 1);
+            }
+        }
+        out
+    }
+    fn needs_retraction(
+        &self,
+        run: Range<usize>,
+        selection: &HashSet<Id>,
+        retract: &Retract,
+    ) -> bool {
+        let lines = &self.lines[run];
+        let retracted = lines.iter().any(|id| {
+            let firmware = self
+                .instructions
+                .get(id)
+                .is_some_and(|ins| ins.first_word.0 == 'G' && ins.first_word.1 == 10.0);
+            let pulled = self.vertices.get(id).is_some_and(|v| v.to.e < 0.0);
+            firmware || pulled
+        });
+        let travels: Vec<&Id> = lines
+            .iter()
+            .filter(|id| {
+                self.vertices
+                    .get(id)
+                    .is_some_and(|v| v.label == Label::TravelMove)
+            })
+            .collect();
+        if retracted || travels.is_empty() {
+            return false;
+        }
+        if selection.is_empty() {
+            let length: f32 = travels.iter().map(|id| self.dist_from_prev(id)).sum();
+            length >= retract.min_travel
+        } else {
+            travels.iter().any(|id| selection.contains(id))
+        }
+    }
+    // the run's lines wrapped in a retraction, hop, lower and deretraction
+    fn retract_run(&mut self, run: Range<usize>, retract: &Retract) -> Vec<Id> {
+        let lines = self.lines[run.clone()].to_vec();
+        let start = self.pos_before(run.start).unwrap_or(Pos::home());
+        let end = self.pos_before(run.end).unwrap_or(start);
+        let hop = if retract.hop == ZHop::Off {
+            0.0
+        } else {
+            retract.hop_height.max(0.0)
+        };
+        let mut out = Vec::new();
+        if retract.firmware {
+            out.push(self.new_instruction('G', 10.0));
+        } else {
+            out.push(self.new_move(Pos {
+                e: -retract.length,
+                f: retract.speed,
+                ..start
+            }));
+        }
+        match retract.hop {
+            _ if hop == 0.0 => {}
+            ZHop::Straight => out.push(self.new_move(Pos {
+                z: start.z + hop,
+                e: 0.0,
+                f: retract.hop_speed,
+                ..start
+            })),
+            ZHop::Spiral => {
+                // a circle through the start, centred off to one side
+                for step in 1..=SPIRAL_STEPS {
+                    let t = step as f32 / SPIRAL_STEPS as f32;
+                    out.push(self.new_move(Pos {
+                        x: start.x - SPIRAL_RADIUS + SPIRAL_RADIUS * (t * TAU).cos(),
+                        y: start.y + SPIRAL_RADIUS * (t * TAU).sin(),
+                        z: start.z + hop * t,
+                        e: 0.0,
+                        f: retract.hop_speed,
+                    }));
+                }
+            }
+            _ => {}
+        }
+        // everything on the way goes over the top
+        for id in &lines {
+            if let Some(v) = self.vertices.get_mut(id) {
+                v.to.z += hop;
+            }
+        }
+        out.extend(&lines);
+        if hop > 0.0 {
+            out.push(self.new_move(Pos {
+                e: 0.0,
+                f: retract.hop_speed,
+                ..end
+            }));
+        }
+        if retract.firmware {
+            out.push(self.new_instruction('G', 11.0));
+        } else {
+            out.push(self.new_move(Pos {
+                e: retract.length,
+                f: retract.prime_speed,
+                ..end
+            }));
+        }
+        out
+    }
+    // labelled once it's in the line order
+    fn new_move(&mut self, to: Pos) -> Id {
+        let v = Vertex {
+            id: self.id_counter.get(),
+            count: 0,
+            label: Label::Uninitialized,
+            to,
+        };
+        self.vertices.insert(v.id, v);
+        v.id
+    }
+    fn new_instruction(&mut self, letter: char, number: f32) -> Id {
+        let id = self.id_counter.get();
+        let ins = Instruction::build(vec![Word(letter, number, None)]);
+        self.instructions.insert(id, ins);
+        id
+    }
+}
+
+#[test]
+fn hop_over_travel() {
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    G1 X30 Y40 F6000
+    G1 X10 E1 F1200";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let retract = Retract {
+        length: 0.8,
+        speed: 2400.0,
+        prime_speed: 1800.0,
+        firmware: false,
+        hop: ZHop::Straight,
+        hop_height: 0.4,
+        hop_speed: 600.0,
+        min_travel: 2.0,
+    };
+    assert_eq!(gcode.insert_retractions(&HashSet::new(), &retract), 1);
+    let moves: Vec<&Vertex> = gcode
+        .lines
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .collect();
+    let labels: Vec<Label> = moves[2..].iter().map(|v| v.label).collect();
+    assert_eq!(
+        labels,
+        vec![
+            Label::PlanarExtrustion,
+            Label::Retraction,
+            Label::LiftZ,
+            Label::TravelMove,
+            Label::LowerZ,
+            Label::DeRetraction,
+            Label::PlanarExtrustion,
+        ]
+    );
+    // the deretraction primes in place
+    let (lower, prime) = (moves[6].to, moves[7].to);
+    assert_eq!((prime.x, prime.y, prime.e), (lower.x, lower.y, 0.8));
+    assert_eq!(moves[5].to.z, 0.6);
+    let e: f32 = moves.iter().map(|v| v.to.e).sum();
+    assert!((e - 2.0).abs() < 1e-6);
+    // already retracted
+    assert_eq!(gcode.insert_retractions(&HashSet::new(), &retract), 0);
+}
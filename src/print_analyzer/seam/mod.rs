@@ -0,0 +1,343 @@
+use super::contour::Path;
+use super::{Id, Label, Parsed, Pos, Vertex};
+use bevy::math::{Vec2, Vec3};
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SeamStrategy {
+    // the point of the loop closest to a picked point
+    Nearest(Vec2),
+    // the point furthest towards the back of the bed
+    Rear,
+    // where the loop crosses the line through both points, closest to the second
+    Aligned(Vec2, Vec2),
+    // anywhere along the loop, the same seed picks the same spots
+    Random(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Seam {
+    // index into the paths
+    pub path: usize,
+    // first move of the loop
+    pub id: Id,
+    pub point: Vec3,
+}
+
+// splitmix64, enough to scatter seams without pulling in a crate
+fn random(seed: u64) -> f32 {
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    (z >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// the point `dist` mm along a polyline, its end if it's shorter
+fn point_along(points: &[Vec2], mut dist: f32) -> Vec2 {
+    for w in points.windows(2) {
+        let len = w[0].distance(w[1]);
+        if dist <= len && len > 0.0 {
+            return w[0].lerp(w[1], dist / len);
+        }
+        dist -= len;
+    }
+    points.last().copied().unwrap_or(Vec2::ZERO)
+}
+
+impl Path {
+    // move of the loop the seam goes on, and how far along it
+    fn locate(&self, strategy: SeamStrategy, index: usize) -> Option<(usize, f32)> {
+        let segments = || {
+            self.points
+                .windows(2)
+                .enumerate()
+                .map(|(k, w)| (k, w[0], w[1]))
+        };
+        match strategy {
+            SeamStrategy::Nearest(p) => segments()
+                .map(|(k, a, b)| {
+                    let ab = b - a;
+                    let t = if ab.length_squared() > 0.0 {
+                        ((p - a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+                    } else {
+                        1.0
+                    };
+                    (k, t, p.distance(a + ab * t))
+                })
+                .min_by(|x, y| x.2.total_cmp(&y.2))
+                .map(|(k, t, _)| (k, t)),
+            SeamStrategy::Rear => {
+                segments().fold(None, |best: Option<(usize, f32)>, (k, _, b)| match best {
+                    Some((i, _)) if self.points[i + 1].y >= b.y => best,
+                    _ => Some((k, 1.0)),
+                })
+            }
+            SeamStrategy::Aligned(from, to) => {
+                let d = to - from;
+                segments()
+                    .filter_map(|(k, a, b)| {
+                        let (sa, sb) = (d.perp_dot(a - from), d.perp_dot(b - from));
+                        if sa * sb > 0.0 || sa == sb {
+                            return None;
+                        }
+                        let t = sa / (sa - sb);
+                        Some((k, t, to.distance(a.lerp(b, t))))
+                    })
+                    .min_by(|x, y| x.2.total_cmp(&y.2))
+                    .map(|(k, t, _)| (k, t))
+                    // loops the line misses get the closest point instead
+                    .or_else(|| self.locate(SeamStrategy::Nearest(to), index))
+            }
+            SeamStrategy::Random(seed) => {
+                let mut left =
+                    random(seed ^ (index as u64).wrapping_mul(0x2545_f491)) * self.length();
+                segments().find_map(|(k, a, b)| {
+                    let len = a.distance(b);
+                    if left <= len && len > 0.0 {
+                        Some((k, left / len))
+                    } else {
+                        left -= len;
+                        None
+                    }
+                })
+            }
+        }
+    }
+}
+
+impl Parsed {
+    // where every loop starts and ends
+    pub fn seams(&self, paths: &[Path]) -> Vec<Seam> {
+        paths
+            .iter()
+            .enumerate()
+            .filter(|(_, path)| path.closed)
+            .map(|(i, path)| {
+                let id = path.vertices[0];
+                Seam {
+                    path: i,
+                    id,
+                    point: path.points[0].extend(self.vertices[&id].to.z),
+                }
+            })
+            .collect()
+    }
+    // move the seam of every loop with a line in `ids`, or of every loop if it's empty.
+    // returns how many loops changed
+    pub fn relocate_seams(&mut self, ids: &HashSet<Id>, strategy: SeamStrategy) -> usize {
+        let paths = self.paths();
+        let mut edits = Vec::new();
+        let mut moved = Vec::new();
+        for (index, path) in paths.iter().enumerate() {
+            if !path.closed || !(ids.is_empty() || path.vertices.iter().any(|id| ids.contains(id)))
+            {
+                continue;
+            }
+            if let Some(edit) = self.rotate_loop(path, strategy, index, &mut moved) {
+                edits.push(edit);
+            }
+        }
+        let count = edits.len();
+        if count > 0 {
+            self.replace_lines(edits);
+            self.reindex(&moved);
+        }
+        count
+    }
+    // the lines of a loop starting from its new seam. moves leading up to the old seam
+    // are pointed at the new one and added to `moved`
+    fn rotate_loop(
+        &mut self,
+        path: &Path,
+        strategy: SeamStrategy,
+        index: usize,
+        moved: &mut Vec<Id>,
+    ) -> Option<(std::ops::Range<usize>, Vec<Id>)> {
+        let (k, t) = path.locate(strategy, index)?;
+        // the seam always ends up at the end of a move
+        let (k, t) = match (k, t) {
+            (0, t) if t < 1e-3 => return None,
+            (k, t) if t < 1e-3 => (k - 1, 1.0),
+            _ => (k, t),
+        };
+        let last = path.vertices.len() - 1;
+        if k == last && t > 1.0 - 1e-3 {
+            return None;
+        }
+        let first = path.vertices[0];
+        let start = self.lines.position(&first)?;
+        let end = self.lines.position(&path.vertices[last])? + 1;
+        let mut lines = self.lines[start..end].to_vec();
+        let mut seam = path.vertices[k];
+        let point = path.points[k].lerp(path.points[k + 1], t.min(1.0));
+        if t < 1.0 - 1e-3 {
+            let split = self.split_vertex(&seam, t);
+            let i = lines.iter().position(|id| *id == seam)?;
+            lines.insert(i, split);
+            seam = split;
+        }
+        let cut = lines.iter().position(|id| *id == seam)? + 1;
+        lines.rotate_left(cut);
+        // the loop from its new seam round to it again
+        let around: Vec<Vec2> = std::iter::once(point)
+            .chain(lines.iter().filter_map(|id| {
+                let v = self.vertices.get(id).filter(|v| v.extrusion_move())?;
+                Some(Vec2::new(v.to.x, v.to.y))
+            }))
+            .collect();
+        self.move_trail(path.vertices[last], seam, &around, moved);
+        // the travel onto the loop and anything done in place after it
+        let old = path.points[0];
+        let mut lead = Vec::new();
+        let mut prev = self.lines.prev(&first);
+        while let Some(id) = prev {
+            let v = self.vertices[&id];
+            if v.extrusion_move() || Vec2::new(v.to.x, v.to.y).distance(old) > 1e-4 {
+                break;
+            }
+            lead.push(id);
+            prev = self.lines.prev(&id);
+        }
+        for id in &lead {
+            let v = self.vertices.get_mut(id)?;
+            v.to.x = point.x;
+            v.to.y = point.y;
+        }
+        // labels once everything is in place, a move can only be judged from where it starts
+        for id in lead.iter().rev() {
+            let from = self.vertices[id].get_from(self);
+            self.vertices.get_mut(id)?.label(&from, self.preprint_edge);
+        }
+        moved.extend(&lead);
+        if lead.is_empty() {
+            // the loop was printed straight on from the line before, travel to the new seam
+            let from = self.vertices[&first].get_from(self);
+            let mut travel = Vertex {
+                id: self.id_counter.get(),
+                count: 0,
+                label: Label::Uninitialized,
+                to: Pos {
+                    x: point.x,
+                    y: point.y,
+                    e: 0.0,
+                    ..from
+                },
+            };
+            travel.label(&from, self.preprint_edge);
+            self.vertices.insert(travel.id, travel);
+            lines.insert(0, travel.id);
+        }
+        Some((start..end, lines))
+    }
+    // wipes after the loop that ended at `old_end` are laid back along the loop from its new
+    // end `seam`, anything done in place goes with them. the move leaving is added to `moved`
+    // too, it starts from somewhere else now
+    fn move_trail(&mut self, old_end: Id, seam: Id, around: &[Vec2], moved: &mut Vec<Id>) {
+        let mut trail = Vec::new();
+        let mut next = self.lines.next(&old_end);
+        while let Some(id) = next {
+            let v = self.vertices[&id];
+            let from = v.get_from(self);
+            let in_place = v.to.x == from.x && v.to.y == from.y;
+            if v.extrusion_move() || !(in_place || v.label == Label::Wipe) {
+                break;
+            }
+            let length = Vec2::new(from.x, from.y).distance(Vec2::new(v.to.x, v.to.y));
+            trail.push((id, length));
+            next = self.lines.next(&id);
+        }
+        let mut prev = self.vertices[&seam].to;
+        let mut along = 0.0;
+        for (id, length) in trail {
+            along += length;
+            let at = point_along(around, along);
+            let v = self.vertices.get_mut(&id).unwrap();
+            v.to.x = at.x;
+            v.to.y = at.y;
+            v.label(&prev, self.preprint_edge);
+            prev = v.to;
+            moved.push(id);
+        }
+        if let Some(v) = next.and_then(|id| self.vertices.get_mut(&id)) {
+            v.label(&prev, self.preprint_edge);
+            moved.push(v.id);
+        }
+    }
+    // split a move at `t` along it. the new vertex leads up to it and takes its share of e,
+    // it still has to be put into the line order
+    fn split_vertex(&mut self, id: &Id, t: f32) -> Id {
+        let v = self.vertices[id];
+        let from = v.get_from(self);
+        let mut new = Vertex {
+            id: self.id_counter.get(),
+            count: 0,
+            label: Label::Uninitialized,
+            to: Pos {
+                x: from.x + (v.to.x - from.x) * t,
+                y: from.y + (v.to.y - from.y) * t,
+                z: from.z + (v.to.z - from.z) * t,
+                e: v.to.e * t,
+                f: v.to.f,
+            },
+        };
+        new.label(&from, self.preprint_edge);
+        self.vertices.insert(new.id, new);
+        if let Some(v) = self.vertices.get_mut(id) {
+            v.to.e *= 1.0 - t;
+        }
+        new.id
+    }
+}
+
+#[test]
+fn rotate_and_split() {
+    let gcode = "G28
+    G1 X6 Y6 Z0.2 F1200
+    G1 X10 Y10
+    G1 X30 E1
+    G1 Y30 E1
+    G1 X10 E1
+    G1 Y10 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let total = |gcode: &Parsed| gcode.vertices.values().map(|v| v.to.e).sum::<f32>();
+    let seam = |gcode: &Parsed| gcode.seams(&gcode.paths())[0].point;
+    assert_eq!(seam(&gcode), Vec3::new(10.0, 10.0, 0.2));
+    assert_eq!(gcode.relocate_seams(&HashSet::new(), SeamStrategy::Rear), 1);
+    assert_eq!(seam(&gcode), Vec3::new(30.0, 30.0, 0.2));
+    assert_eq!(total(&gcode), 4.0);
+    let travel = gcode.vertices[&gcode.lines[2]];
+    assert_eq!((travel.to.x, travel.to.y), (30.0, 30.0));
+    let nearest = SeamStrategy::Nearest(Vec2::new(20.0, 9.0));
+    assert_eq!(gcode.relocate_seams(&HashSet::new(), nearest), 1);
+    assert_eq!(seam(&gcode), Vec3::new(20.0, 10.0, 0.2));
+    assert_eq!(total(&gcode), 4.0);
+    assert_eq!(gcode.lines.len(), 8);
+    let last = gcode.vertices[&gcode.lines[7]];
+    assert_eq!((last.to.x, last.to.y, last.to.e), (20.0, 10.0, 0.5));
+    // the wipe goes along the loop from the new seam, the lift stays with it
+    let wiped = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    G1 Y30 E1
+    G1 X10 E1
+    G1 Y10 E1
+    G1 X14 E-0.4
+    G1 Z0.6
+    G1 X50 Y50";
+    let mut gcode = Parsed::build(wiped, true).expect("failed to parse");
+    assert_eq!(gcode.relocate_seams(&HashSet::new(), SeamStrategy::Rear), 1);
+    let trail: Vec<(f32, f32, Label)> = gcode.lines[6..]
+        .iter()
+        .map(|id| gcode.vertices[id])
+        .map(|v| (v.to.x, v.to.y, v.label))
+        .collect();
+    assert_eq!(
+        trail,
+        vec![
+            (26.0, 30.0, Label::Wipe),
+            (26.0, 30.0, Label::LiftZ),
+            (50.0, 50.0, Label::TravelMove)
+        ]
+    );
+}
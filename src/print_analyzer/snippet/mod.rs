@@ -0,0 +1,310 @@
+use super::file_reader;
+use super::{Id, Instruction, Label, Parsed, Pos, Vertex, Word, G1};
+use std::collections::HashSet;
+use std::ops::Range;
+
+// what a selected line stands for when placing a snippet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Feature {
+    Vertex,
+    Shape,
+    Layer,
+}
+
+impl Parsed {
+    // line ranges of the features the selection touches, in print order
+    pub fn features(&self, selection: &HashSet<Id>, feature: Feature) -> Vec<Range<usize>> {
+        let mut out: Vec<Range<usize>> = match feature {
+            Feature::Vertex => selection
+                .iter()
+                .filter_map(|id| self.lines.position(id))
+                .map(|i| i..i + 1)
+                .collect(),
+            Feature::Shape => self
+                .shapes
+                .iter()
+                .filter(|s| s.lines.iter().any(|id| selection.contains(id)))
+                .filter_map(|s| {
+                    let start = self.lines.position(s.lines.first()?)?;
+                    let end = self.lines.position(s.lines.last()?)?;
+                    Some(start..end + 1)
+                })
+                .collect(),
+            Feature::Layer => self
+                .layers
+                .iter()
+                .filter(|l| {
+                    self.lines[l.lines.clone()]
+                        .iter()
+                        .any(|id| selection.contains(id))
+                })
+                .map(|l| l.lines.clone())
+                .collect(),
+        };
+        out.sort_by_key(|r| r.start);
+        out.dedup();
+        out
+    }
+    pub fn insert_before(
+        &mut self,
+        features: &[Range<usize>],
+        snippet: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let edits = features
+            .iter()
+            .map(|r| (r.start..r.start, r.start))
+            .collect();
+        self.splice(edits, snippet)
+    }
+    pub fn insert_after(
+        &mut self,
+        features: &[Range<usize>],
+        snippet: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let edits = features.iter().map(|r| (r.end..r.end, r.start)).collect();
+        self.splice(edits, snippet)
+    }
+    // features that overlap one already replaced are left alone
+    pub fn replace_with(
+        &mut self,
+        features: &[Range<usize>],
+        snippet: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut end = 0;
+        let mut edits = Vec::new();
+        for r in features {
+            if r.start >= end {
+                edits.push((r.clone(), r.start));
+                end = r.end;
+            }
+        }
+        self.splice(edits, snippet)
+    }
+    // put the snippet in place of each range, with placeholders filled in from the line
+    // at the paired position. moves in the snippet start from wherever the print was, and
+    // travel back to where the print carries on from
+    pub(super) fn splice(
+        &mut self,
+        edits: Vec<(Range<usize>, usize)>,
+        snippet: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut out = Vec::new();
+        let mut after = Vec::new();
+        for (range, at) in edits {
+            let start = self.pos_before(range.start);
+            let resume = self.pos_before(range.end);
+            let text = self.fill_placeholders(snippet, at);
+            let mut ids = match self.build_snippet(&text, start) {
+                Ok(ids) => ids,
+                Err(e) => {
+                    // leave the print as it was
+                    for id in out.iter().flat_map(|(_, ids)| ids) {
+                        self.vertices.remove(id);
+                        self.instructions.remove(id);
+                    }
+                    return Err(e);
+                }
+            };
+            let last = ids
+                .iter()
+                .rev()
+                .find_map(|id| self.vertices.get(id))
+                .map(|v| v.to)
+                .or(start);
+            if let (Some(last), Some(resume)) = (last, resume) {
+                if last.dist(&resume) > f32::EPSILON {
+                    let mut back = Vertex {
+                        id: self.id_counter.get(),
+                        count: 0,
+                        label: Label::Uninitialized,
+                        to: Pos {
+                            e: 0.0,
+                            f: last.f,
+                            ..resume
+                        },
+                    };
+                    back.label(&last, self.preprint_edge);
+                    self.vertices.insert(back.id, back);
+                    ids.push(back.id);
+                }
+            }
+            if let Some(id) = self.lines.get(range.end) {
+                after.push(*id);
+            }
+            out.push((range, ids));
+        }
+        for (range, _) in &out {
+            for id in &self.lines[range.clone()] {
+                self.vertices.remove(id);
+                self.instructions.remove(id);
+                self.index.remove(id);
+            }
+        }
+        self.replace_lines(out);
+        // the first move after each snippet starts from somewhere new
+        for id in &after {
+            if let Some(v) = self.vertices.get(id) {
+                let from = v.get_from(self);
+                self.vertices
+                    .get_mut(id)
+                    .unwrap()
+                    .label(&from, self.preprint_edge);
+            }
+        }
+        self.reindex(&after);
+        Ok(())
+    }
+    // where the print is before the line at `i`
+    pub(super) fn pos_before(&self, i: usize) -> Option<Pos> {
+        self.lines[..i.min(self.lines.len())]
+            .iter()
+            .rev()
+            .find_map(|id| self.vertices.get(id))
+            .map(|v| v.to)
+    }
+    // {layer}, {z} and {tool} for the line at `i`
+    fn fill_placeholders(&self, snippet: &str, i: usize) -> String {
+        let id = self.lines.get(i).copied();
+        let layer = id.and_then(|id| self.layer_of(&id));
+        let tool = self.lines[..i.min(self.lines.len())]
+            .iter()
+            .rev()
+            .find_map(|id| match self.instructions.get(id)?.first_word {
+                Word('T', n, None) => Some(n as u32),
+                _ => None,
+            })
+            .unwrap_or(0);
+        snippet
+            .replace("{layer}", &layer.map_or(0, |l| l.index).to_string())
+            .replace("{z}", &layer.map_or(0.0, |l| l.z).to_string())
+            .replace("{tool}", &tool.to_string())
+    }
+    // parse the snippet into new vertices and instructions, not yet in the line order.
+    // the snippet's own G90/G91 and M82/M83 only apply to it
+    fn build_snippet(
+        &mut self,
+        text: &str,
+        start: Option<Pos>,
+    ) -> Result<Vec<Id>, Box<dyn std::error::Error>> {
+        let mut ids = Vec::new();
+        match self.snippet_lines(text, start, &mut ids) {
+            Ok(()) => Ok(ids),
+            // nothing references what was built before the error
+            Err(e) => {
+                for id in &ids {
+                    self.vertices.remove(id);
+                    self.instructions.remove(id);
+                }
+                Err(e)
+            }
+        }
+    }
+    fn snippet_lines(
+        &mut self,
+        text: &str,
+        start: Option<Pos>,
+        ids: &mut Vec<Id>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (mut rel_xyz, mut abs_e, mut last_e) = (false, false, 0.0);
+        let mut prev = start;
+        for line in file_reader::parse_str(text) {
+            let mut line = file_reader::split_line(line.trim());
+            if line.is_empty() {
+                continue;
+            }
+            line.reverse();
+            let Word(letter, number, params) = line.pop().unwrap();
+            match (letter, number.round() as i32) {
+                ('G', 0) | ('G', 1) => {
+                    let from = prev.ok_or("snippet moves before the printer is homed")?;
+                    let mut g1 = G1::build(line);
+                    if rel_xyz {
+                        g1.x = g1.x.map(|x| from.x + x);
+                        g1.y = g1.y.map(|y| from.y + y);
+                        g1.z = g1.z.map(|z| from.z + z);
+                    }
+                    if abs_e {
+                        if let Some(e) = g1.e {
+                            g1.e = Some(e - last_e);
+                            last_e = e;
+                        }
+                    }
+                    let mut v = Vertex {
+                        id: self.id_counter.get(),
+                        count: 0,
+                        label: Label::Uninitialized,
+                        to: Pos::build(&from, &g1),
+                    };
+                    v.label(&from, self.preprint_edge);
+                    self.vertices.insert(v.id, v);
+                    ids.push(v.id);
+                    prev = Some(v.to);
+                }
+                ('G', 28) => return Err("can't home in the middle of a print".into()),
+                ('G', 90) => rel_xyz = false,
+                ('G', 91) => rel_xyz = true,
+                ('M', 82) => abs_e = true,
+                ('M', 83) => abs_e = false,
+                (letter, num) => {
+                    if (letter, num) == ('G', 92) {
+                        if let Some(Word(_, e, _)) = line.iter().find(|w| w.0 == 'E') {
+                            last_e = *e;
+                        }
+                    }
+                    line.push(Word(letter, number, params));
+                    let id = self.id_counter.get();
+                    self.instructions.insert(id, Instruction::build(line));
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn park_and_come_back() {
+    use super::emit::Emit;
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    T1
+    G1 Y30 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let corner: HashSet<Id> = [gcode.lines[4]].into_iter().collect();
+    let features = gcode.features(&corner, Feature::Vertex);
+    let snippet = "M117 tool {tool}
+    G91
+    G1 Z5
+    G90
+    G1 X0 Y200 F6000";
+    gcode
+        .insert_before(&features, snippet)
+        .expect("bad snippet");
+    let out = gcode.emit(&gcode, false);
+    let lines: Vec<&str> = out.lines().map(str::trim).collect();
+    let at = lines
+        .iter()
+        .position(|l| *l == "M117 tool 1")
+        .expect("no snippet");
+    assert_eq!(lines[at + 1], "G1 Z5.2");
+    assert_eq!(lines[at + 2], "G1 X0 Y200 F6000");
+    // back to the corner before carrying on
+    assert_eq!(lines[at + 3], "G1 X30 Y10 Z0.2");
+    assert_eq!(lines[at + 4], "G1 Y30 E1 F1200");
+    let whole = gcode.features(&corner, Feature::Vertex);
+    gcode.replace_with(&whole, "M400").expect("bad snippet");
+    // the replaced move is still travelled, the snippet didn't go anywhere
+    let last = gcode.vertices[&gcode.lines[9]];
+    assert_eq!(
+        (last.to.y, last.to.e, last.label),
+        (30.0, 0.0, Label::TravelMove)
+    );
+    // a snippet that fails halfway leaves nothing behind
+    let (vertices, instructions) = (gcode.vertices.len(), gcode.instructions.len());
+    let last: HashSet<Id> = [gcode.lines[9]].into_iter().collect();
+    let at = gcode.features(&last, Feature::Vertex);
+    assert!(gcode.insert_before(&at, "M400\nG1 X5\nG28").is_err());
+    assert_eq!(gcode.vertices.len(), vertices);
+    assert_eq!(gcode.instructions.len(), instructions);
+}
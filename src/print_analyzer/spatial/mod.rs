@@ -0,0 +1,296 @@
+use super::{Id, Parsed};
+use bevy::math::{Vec2, Vec3};
+use std::collections::{BTreeMap, HashMap};
+
+// mm per side of a grid cell
+const CELL: f32 = 2.0;
+// mm around a point searched on the grid before checking every vertex
+const NEAREST_MAX: f32 = 64.0;
+
+// z heights closer than a micron share a layer
+fn layer_key(z: f32) -> i32 {
+    (z * 1000.0).round() as i32
+}
+
+// a move flattened to 2d
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Segment {
+    pub(super) a: Vec2,
+    pub(super) b: Vec2,
+    pub(super) half_width: f32,
+}
+
+impl Segment {
+    pub(super) fn dist(&self, p: Vec2) -> f32 {
+        let ab = self.b - self.a;
+        let t = if ab.length_squared() > 0.0 {
+            ((p - self.a).dot(ab) / ab.length_squared()).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        p.distance(self.a + ab * t)
+    }
+    // shortest distance between two segments, 0 if they cross
+    fn dist_to(&self, other: &Segment) -> f32 {
+        let cross = |o: Vec2, a: Vec2, b: Vec2| (a - o).perp_dot(b - o);
+        let (d1, d2) = (
+            cross(self.a, self.b, other.a),
+            cross(self.a, self.b, other.b),
+        );
+        let (d3, d4) = (
+            cross(other.a, other.b, self.a),
+            cross(other.a, other.b, self.b),
+        );
+        if d1 * d2 < 0.0 && d3 * d4 < 0.0 {
+            return 0.0;
+        }
+        self.dist(other.a)
+            .min(self.dist(other.b))
+            .min(other.dist(self.a))
+            .min(other.dist(self.b))
+    }
+}
+
+// grid over every move, bucketed by the layer the move ends on.
+// each vertex is stored as the segment from the vertex before it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SpatialIndex {
+    segments: HashMap<Id, (Vec3, Vec3)>,
+    layers: BTreeMap<i32, HashMap<(i32, i32), Vec<Id>>>,
+}
+
+impl SpatialIndex {
+    fn cell(p: Vec2) -> (i32, i32) {
+        ((p.x / CELL).floor() as i32, (p.y / CELL).floor() as i32)
+    }
+    // cells overlapped by the box from min to max
+    fn cells(min: Vec2, max: Vec2) -> impl Iterator<Item = (i32, i32)> {
+        let (min, max) = (Self::cell(min), Self::cell(max));
+        (min.0..=max.0).flat_map(move |x| (min.1..=max.1).map(move |y| (x, y)))
+    }
+    // cells the segment from a to b passes through, column by column. the width of a bead
+    // is left to the queries, they widen their search by it
+    fn cells_along(a: Vec2, b: Vec2) -> impl Iterator<Item = (i32, i32)> {
+        let (a, b) = if a.x <= b.x { (a, b) } else { (b, a) };
+        // nothing to walk for a broken move, it is still kept for lookups by id
+        let (first, last) = if a.is_finite() && b.is_finite() {
+            (Self::cell(a).0, Self::cell(b).0)
+        } else {
+            (0, -1)
+        };
+        // exact at the ends so the end points always land in their own cells
+        let y_at = move |x: f32| {
+            if x <= a.x {
+                a.y
+            } else if x >= b.x {
+                b.y
+            } else {
+                a.y + (b.y - a.y) * (x - a.x) / (b.x - a.x)
+            }
+        };
+        (first..=last).flat_map(move |column| {
+            let left = (column as f32 * CELL).max(a.x);
+            let right = ((column + 1) as f32 * CELL).min(b.x);
+            let (y0, y1) = if a.x == b.x {
+                (a.y, b.y)
+            } else {
+                (y_at(left), y_at(right))
+            };
+            let low = (y0.min(y1) / CELL).floor() as i32;
+            let high = (y0.max(y1) / CELL).floor() as i32;
+            (low..=high).map(move |y| (column, y))
+        })
+    }
+    pub fn insert(&mut self, id: Id, from: Vec3, to: Vec3) {
+        self.remove(&id);
+        let layer = self.layers.entry(layer_key(to.z)).or_default();
+        for cell in Self::cells_along(from.truncate(), to.truncate()) {
+            layer.entry(cell).or_default().push(id);
+        }
+        self.segments.insert(id, (from, to));
+    }
+    pub fn remove(&mut self, id: &Id) {
+        let Some((from, to)) = self.segments.remove(id) else {
+            return;
+        };
+        let key = layer_key(to.z);
+        let Some(layer) = self.layers.get_mut(&key) else {
+            return;
+        };
+        for cell in Self::cells_along(from.truncate(), to.truncate()) {
+            if let Some(ids) = layer.get_mut(&cell) {
+                ids.retain(|i| i != id);
+                if ids.is_empty() {
+                    layer.remove(&cell);
+                }
+            }
+        }
+        if layer.is_empty() {
+            self.layers.remove(&key);
+        }
+    }
+    // start and end of the move to a vertex
+    pub fn segment(&self, id: &Id) -> Option<(Vec3, Vec3)> {
+        self.segments.get(id).copied()
+    }
+    // candidates from the cells of layers between min and max, may hold duplicates
+    fn candidates(&self, min: Vec3, max: Vec3) -> impl Iterator<Item = &Id> {
+        self.layers
+            .range(layer_key(min.z)..=layer_key(max.z))
+            .flat_map(move |(_, cells)| {
+                Self::cells(min.truncate(), max.truncate())
+                    .filter_map(|cell| cells.get(&cell))
+                    .flatten()
+            })
+    }
+    // vertices that end inside the box
+    pub fn in_box(&self, min: Vec3, max: Vec3) -> Vec<Id> {
+        let mut out: Vec<Id> = self
+            .candidates(min, max)
+            .filter(|id| {
+                let to = self.segments[id].1;
+                to.cmpge(min).all() && to.cmple(max).all()
+            })
+            .copied()
+            .collect();
+        out.sort_by_key(|id| id.0);
+        out.dedup();
+        out
+    }
+    // vertices that end within radius of center
+    pub fn in_radius(&self, center: Vec3, radius: f32) -> Vec<Id> {
+        let mut out = self.in_box(center - radius, center + radius);
+        out.retain(|id| self.segments[id].1.distance(center) <= radius);
+        out
+    }
+    // vertex ending closest to p
+    pub fn nearest(&self, p: Vec3) -> Option<Id> {
+        if !p.is_finite() {
+            return None;
+        }
+        // grow the search around p, then give up on the grid and check everything
+        let mut found = Vec::new();
+        let mut radius = CELL;
+        while found.is_empty() && radius <= NEAREST_MAX {
+            found = self.in_radius(p, radius);
+            radius *= 2.0;
+        }
+        if found.is_empty() {
+            found = self
+                .segments
+                .iter()
+                .filter(|(_, (_, to))| to.is_finite())
+                .map(|(id, _)| *id)
+                .collect();
+        }
+        found.into_iter().min_by(|a, b| {
+            let (a, b) = (self.segments[a].1, self.segments[b].1);
+            a.distance(p).total_cmp(&b.distance(p))
+        })
+    }
+    // moves on layer z that pass within dist of the line from a to b, 0 for a strict crossing
+    pub fn near_segment(&self, z: f32, a: Vec2, b: Vec2, dist: f32) -> Vec<Id> {
+        let line = Segment {
+            a,
+            b,
+            half_width: 0.0,
+        };
+        let (min, max) = (a.min(b) - dist, a.max(b) + dist);
+        let mut out: Vec<Id> = self
+            .candidates(min.extend(z), max.extend(z))
+            .filter(|id| {
+                let (from, to) = self.segments[id];
+                let seg = Segment {
+                    a: from.truncate(),
+                    b: to.truncate(),
+                    half_width: 0.0,
+                };
+                seg.dist_to(&line) <= dist
+            })
+            .copied()
+            .collect();
+        out.sort_by_key(|id| id.0);
+        out.dedup();
+        out
+    }
+    // moves on layer z that pass within dist of p
+    pub fn near_point(&self, z: f32, p: Vec2, dist: f32) -> Vec<Id> {
+        self.near_segment(z, p, p, dist)
+    }
+}
+
+impl Parsed {
+    pub fn index(&self) -> &SpatialIndex {
+        &self.index
+    }
+    pub fn rebuild_index(&mut self) {
+        self.index = SpatialIndex::default();
+        let ids: Vec<Id> = self.vertices.keys().copied().collect();
+        self.reindex(&ids);
+    }
+    // refresh the segments of vertices and of the moves leaving them after they changed
+    pub(super) fn reindex(&mut self, ids: &[Id]) {
+        for id in ids {
+            let Some(v) = self.vertices.get(id) else {
+                self.index.remove(id);
+                continue;
+            };
+            let to = Vec3::new(v.to.x, v.to.y, v.to.z);
+            let from = v.get_from(self);
+            let from = Vec3::new(from.x, from.y, from.z);
+            let next = self.lines.next(id);
+            self.index.insert(*id, from, to);
+            if let Some(n) = next.and_then(|n| self.vertices.get(&n)) {
+                let next_to = Vec3::new(n.to.x, n.to.y, n.to.z);
+                self.index.insert(n.id, to, next_to);
+            }
+        }
+    }
+}
+
+#[test]
+fn queries() {
+    let gcode = "G28
+    G1 X10 Y10 Z0.2
+    G1 X30 E1
+    G1 Y30 E1
+    G1 Z0.4
+    G1 X10 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let l = gcode.lines.clone();
+    let index = gcode.index();
+    assert_eq!(index.nearest(Vec3::new(29.0, 11.0, 0.2)), Some(l[2]));
+    let mut first: Vec<Id> = index.layers[&layer_key(0.2)]
+        .values()
+        .flatten()
+        .copied()
+        .collect();
+    first.sort_by_key(|id| id.0);
+    first.dedup();
+    assert_eq!(first, [l[1], l[2], l[3]]);
+    let crossing = index.near_segment(0.2, Vec2::new(20.0, 0.0), Vec2::new(20.0, 20.0), 0.0);
+    assert_eq!(crossing, vec![l[2]]);
+    let corner = index.in_radius(Vec3::new(30.0, 30.0, 0.3), 0.5);
+    assert_eq!(corner, vec![l[3], l[4]]);
+    // the lifts to z0.2 and z0.4 bound the first shape
+    assert_eq!(gcode.get_shape(&l[2]), l[2..4]);
+    assert!(gcode.get_shape(&Id(u32::MAX)).is_empty());
+    // moves the corner between l[2] and l[3]
+    gcode.translate(&[l[2]].into_iter().collect(), Vec3::X * 5.0);
+    assert_eq!(
+        gcode.index().nearest(Vec3::new(34.0, 11.0, 0.2)),
+        Some(l[2])
+    );
+    assert_eq!(gcode.index().segment(&l[3]).unwrap().0.x, 35.0);
+    let mut delete = [l[5]].into_iter().collect();
+    gcode.merge_delete(&mut delete);
+    assert!(gcode.index().segment(&l[5]).is_none());
+    // a diagonal takes the cells it crosses, not its whole bounding box
+    let mut index = SpatialIndex::default();
+    index.insert(l[1], Vec3::new(1.0, 1.0, 0.2), Vec3::new(41.0, 41.0, 0.2));
+    assert!(index.layers[&layer_key(0.2)].len() <= 2 * 21);
+    assert_eq!(index.nearest(Vec3::new(500.0, 500.0, 0.2)), Some(l[1]));
+    assert_eq!(index.nearest(Vec3::NAN), None);
+    index.remove(&l[1]);
+    assert!(index.layers.is_empty());
+}
@@ -0,0 +1,292 @@
+use super::Id;
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, Index, Range};
+
+// payloads indexed by id. ids are handed out in increasing order and never reused,
+// so a line keeps its slot for as long as it exists
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slots<T> {
+    slots: Vec<Option<(Id, T)>>,
+    len: usize,
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Slots {
+            slots: Vec::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> Slots<T> {
+    pub fn get(&self, id: &Id) -> Option<&T> {
+        self.slots.get(id.0 as usize)?.as_ref().map(|(_, t)| t)
+    }
+    pub fn get_mut(&mut self, id: &Id) -> Option<&mut T> {
+        self.slots.get_mut(id.0 as usize)?.as_mut().map(|(_, t)| t)
+    }
+    pub fn contains_key(&self, id: &Id) -> bool {
+        self.get(id).is_some()
+    }
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (&Id, &T)> {
+        self.slots.iter().flatten().map(|(id, t)| (id, t))
+    }
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&Id, &mut T)> {
+        self.slots.iter_mut().flatten().map(|(id, t)| (&*id, t))
+    }
+    pub fn keys(&self) -> impl Iterator<Item = &Id> {
+        self.iter().map(|(id, _)| id)
+    }
+    pub fn values(&self) -> impl Iterator<Item = &T> {
+        self.iter().map(|(_, t)| t)
+    }
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.iter_mut().map(|(_, t)| t)
+    }
+    // only `Parsed` adds and removes lines, so the order and the vertex chain stay in sync
+    pub(super) fn insert(&mut self, id: Id, value: T) -> Option<T> {
+        let i = id.0 as usize;
+        if self.slots.len() <= i {
+            self.slots.resize_with(i + 1, || None);
+        }
+        let old = self.slots[i].replace((id, value)).map(|(_, t)| t);
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+    pub(super) fn remove(&mut self, id: &Id) -> Option<T> {
+        let old = self.slots.get_mut(id.0 as usize)?.take().map(|(_, t)| t);
+        if old.is_some() {
+            self.len -= 1;
+        }
+        old
+    }
+}
+
+impl<T> Index<&Id> for Slots<T> {
+    type Output = T;
+    fn index(&self, id: &Id) -> &T {
+        self.get(id).expect("no line with that id")
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Link {
+    // index into the order
+    pos: usize,
+    // closest vertices before and after the line
+    prev: Option<Id>,
+    next: Option<Id>,
+}
+
+// file order of every line, along with the chain of vertices through it.
+// the chain is derived from the order, edits only ever change the order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Order {
+    ids: Vec<Id>,
+    links: Vec<Option<Link>>,
+    last_vertex: Option<Id>,
+}
+
+impl Deref for Order {
+    type Target = [Id];
+    fn deref(&self) -> &[Id] {
+        &self.ids
+    }
+}
+
+impl IntoIterator for Order {
+    type Item = Id;
+    type IntoIter = std::vec::IntoIter<Id>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.ids.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Order {
+    type Item = &'a Id;
+    type IntoIter = std::slice::Iter<'a, Id>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.ids.iter()
+    }
+}
+
+impl Order {
+    fn link(&self, id: &Id) -> Option<&Link> {
+        self.links.get(id.0 as usize)?.as_ref()
+    }
+    fn link_mut(&mut self, id: &Id) -> &mut Option<Link> {
+        let i = id.0 as usize;
+        if self.links.len() <= i {
+            self.links.resize(i + 1, None);
+        }
+        &mut self.links[i]
+    }
+    // index of a line in the file
+    pub fn position(&self, id: &Id) -> Option<usize> {
+        self.link(id).map(|l| l.pos)
+    }
+    // closest vertex before a line
+    pub fn prev(&self, id: &Id) -> Option<Id> {
+        self.link(id)?.prev
+    }
+    // closest vertex after a line
+    pub fn next(&self, id: &Id) -> Option<Id> {
+        self.link(id)?.next
+    }
+    pub(super) fn push(&mut self, id: Id, vertex: bool) {
+        let pos = self.ids.len();
+        if vertex {
+            // the last vertex and the lines after it now have a next vertex
+            let start = self
+                .last_vertex
+                .and_then(|v| self.position(&v))
+                .unwrap_or(0);
+            for i in start..pos {
+                let line = self.ids[i];
+                if let Some(link) = self.link_mut(&line) {
+                    link.next = Some(id);
+                }
+            }
+        }
+        let prev = self.last_vertex;
+        *self.link_mut(&id) = Some(Link {
+            pos,
+            prev,
+            next: None,
+        });
+        self.ids.push(id);
+        if vertex {
+            self.last_vertex = Some(id);
+        }
+    }
+    // insert each batch of lines in front of the line it's keyed by, in one pass
+    pub(super) fn insert_before(
+        &mut self,
+        mut batches: HashMap<Id, Vec<Id>>,
+        vertex: impl Fn(&Id) -> bool,
+    ) {
+        let mut ids =
+            Vec::with_capacity(self.ids.len() + batches.values().map(Vec::len).sum::<usize>());
+        for id in &self.ids {
+            if let Some(batch) = batches.remove(id) {
+                ids.extend(batch);
+            }
+            ids.push(*id);
+        }
+        self.ids = ids;
+        self.relink(vertex);
+    }
+    // insert lines at the positions they will have afterwards, in one pass
+    pub(super) fn insert_at(&mut self, mut lines: Vec<(usize, Id)>, vertex: impl Fn(&Id) -> bool) {
+        lines.sort_by_key(|(i, _)| *i);
+        let mut lines = lines.into_iter().peekable();
+        let mut old = std::mem::take(&mut self.ids).into_iter();
+        loop {
+            let id = match lines.peek() {
+                Some((i, _)) if *i <= self.ids.len() => lines.next().map(|(_, id)| id),
+                _ => old.next().or_else(|| lines.next().map(|(_, id)| id)),
+            };
+            let Some(id) = id else {
+                break;
+            };
+            self.ids.push(id);
+        }
+        self.relink(vertex);
+    }
+    // swap each range of lines for the lines paired with it, in one pass.
+    // the ranges can't overlap
+    pub(super) fn replace(
+        &mut self,
+        mut edits: Vec<(Range<usize>, Vec<Id>)>,
+        vertex: impl Fn(&Id) -> bool,
+    ) {
+        edits.sort_by_key(|(range, _)| range.start);
+        let mut edits = edits.into_iter().peekable();
+        let old = std::mem::take(&mut self.ids);
+        let mut i = 0;
+        while i < old.len() {
+            match edits.next_if(|(range, _)| range.start == i) {
+                Some((range, ids)) => {
+                    for id in &old[range.clone()] {
+                        *self.link_mut(id) = None;
+                    }
+                    self.ids.extend(ids);
+                    i = range.end;
+                }
+                None => {
+                    self.ids.push(old[i]);
+                    i += 1;
+                }
+            }
+        }
+        for (_, ids) in edits {
+            self.ids.extend(ids);
+        }
+        self.relink(vertex);
+    }
+    pub(super) fn remove(&mut self, ids: &HashSet<Id>, vertex: impl Fn(&Id) -> bool) {
+        self.ids.retain(|id| !ids.contains(id));
+        for id in ids {
+            *self.link_mut(id) = None;
+        }
+        self.relink(vertex);
+    }
+    // rebuild positions and the vertex chain after the order changed
+    fn relink(&mut self, vertex: impl Fn(&Id) -> bool) {
+        let mut prev = None;
+        for pos in 0..self.ids.len() {
+            let id = self.ids[pos];
+            *self.link_mut(&id) = Some(Link {
+                pos,
+                prev,
+                next: None,
+            });
+            if vertex(&id) {
+                prev = Some(id);
+            }
+        }
+        self.last_vertex = prev;
+        let mut next = None;
+        for i in (0..self.ids.len()).rev() {
+            let id = self.ids[i];
+            if let Some(link) = self.link_mut(&id) {
+                link.next = next;
+            }
+            if vertex(&id) {
+                next = Some(id);
+            }
+        }
+    }
+}
+
+#[test]
+fn chain_follows_order() {
+    let (a, b, c, d) = (Id(0), Id(1), Id(2), Id(3));
+    let vertex = |id: &Id| *id != b;
+    let mut order = Order::default();
+    for id in [a, b, c] {
+        order.push(id, vertex(&id));
+    }
+    assert_eq!(order.prev(&c), Some(a));
+    assert_eq!(order.next(&b), Some(c));
+    order.insert_before([(c, vec![d])].into_iter().collect(), vertex);
+    assert_eq!(&order[..], &[a, b, d, c]);
+    assert_eq!((order.prev(&c), order.next(&a)), (Some(d), Some(d)));
+    order.remove(&[d].into_iter().collect(), vertex);
+    assert_eq!(order.prev(&c), Some(a));
+    order.insert_at(vec![(0, d)], vertex);
+    assert_eq!(&order[..], &[d, a, b, c]);
+    assert_eq!(order.position(&c), Some(3));
+    order.replace(vec![(1..4, vec![c, a])], vertex);
+    assert_eq!(&order[..], &[d, c, a]);
+    assert_eq!((order.prev(&a), order.position(&b)), (Some(c), None));
+}
@@ -0,0 +1,201 @@
+use super::tune::Adjust;
+use super::{Id, Label, Parsed, Pos, Vertex};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TowerParam {
+    // hotend temperature
+    Temperature,
+    // multiplies the feedrate
+    Speed,
+    // multiplies e
+    Flow,
+    // M900 K, or SET_PRESSURE_ADVANCE on klipper
+    PressureAdvance { klipper: bool },
+    // mm pulled back by each retraction
+    Retraction,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bands {
+    // mm of height per band, counted up from the bed
+    Height(f32),
+    Layers(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tower {
+    pub param: TowerParam,
+    // value of the first band, each band after it adds `step`
+    pub start: f32,
+    pub step: f32,
+    pub bands: Bands,
+    // layers at the bottom left as they are, e.g. a base
+    pub skip: usize,
+}
+
+impl Tower {
+    fn band(&self, index: usize, z: f32) -> usize {
+        match self.bands {
+            Bands::Height(h) => ((z - 1e-4) / h.max(1e-3)).floor().max(0.0) as usize,
+            Bands::Layers(n) => (index - self.skip) / n.max(1),
+        }
+    }
+    fn value(&self, band: usize) -> f32 {
+        self.start + self.step * band as f32
+    }
+}
+
+impl Parsed {
+    // rewrite the print into a tuning tower, every band gets the next value of the table.
+    // returns how many bands there are
+    pub fn calibration_tower(
+        &mut self,
+        tower: &Tower,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        // line the band starts at, its value and its lines
+        let mut bands: Vec<(usize, f32, HashSet<Id>)> = Vec::new();
+        let mut current = None;
+        for layer in self.layers.iter().skip(tower.skip) {
+            let band = tower.band(layer.index, layer.z);
+            if current != Some(band) {
+                current = Some(band);
+                bands.push((layer.lines.start, tower.value(band), HashSet::new()));
+            }
+            if let Some((_, _, lines)) = bands.last_mut() {
+                lines.extend(&self.lines[layer.lines.clone()]);
+            }
+        }
+        for (_, value, lines) in &bands {
+            match tower.param {
+                TowerParam::Speed => self.adjust_feedrate(lines, Adjust::Multiply, *value, *value),
+                TowerParam::Flow => self.adjust_flow(lines, Adjust::Multiply, *value, *value),
+                TowerParam::Retraction => self.set_retractions(lines, *value),
+                _ => {}
+            }
+        }
+        // last first, so the positions of the earlier ones hold
+        for (start, value, _) in bands.iter().rev() {
+            let command = match tower.param {
+                TowerParam::Temperature => format!("M104 S{}", value.round()),
+                TowerParam::PressureAdvance { klipper: false } => format!("M900 K{}", value),
+                TowerParam::PressureAdvance { klipper: true } => {
+                    format!("SET_PRESSURE_ADVANCE ADVANCE={}", value)
+                }
+                _ => continue,
+            };
+            self.splice(vec![(*start..*start, *start)], &command)?;
+        }
+        Ok(bands.len())
+    }
+    // pull back `length` mm at every retraction among the lines, and push the same amount
+    // more or less back in at the move that primes after it. where it goes straight back
+    // to extruding, the difference gets its own prime at the end of the travel
+    fn set_retractions(&mut self, lines: &HashSet<Id>, length: f32) {
+        let retractions: Vec<Id> = self
+            .lines
+            .iter()
+            .filter(|id| {
+                lines.contains(id)
+                    && self
+                        .vertices
+                        .get(id)
+                        .is_some_and(|v| v.label == Label::Retraction)
+            })
+            .copied()
+            .collect();
+        let mut primes = HashMap::new();
+        for id in retractions {
+            let v = self.vertices.get_mut(&id).unwrap();
+            let change = length + v.to.e;
+            v.to.e = -length;
+            let mut next = self.lines.next(&id);
+            while let Some(n) = next {
+                if let Some(v) = self.vertices.get(&n) {
+                    if v.to.e > 0.0 {
+                        // priming in place, as opposed to extruding straight away
+                        if self.dist_from_prev(&n) < f32::EPSILON {
+                            self.vertices.get_mut(&n).unwrap().to.e += change;
+                        } else if change.abs() > f32::EPSILON {
+                            let from = self.vertices[&self.lines.prev(&n).unwrap()].to;
+                            let mut prime = Vertex {
+                                id: self.id_counter.get(),
+                                count: 0,
+                                label: Label::Uninitialized,
+                                to: Pos { e: change, ..from },
+                            };
+                            prime.label(&from, self.preprint_edge);
+                            self.vertices.insert(prime.id, prime);
+                            primes.insert(n, vec![prime.id]);
+                        }
+                        break;
+                    }
+                }
+                next = self.lines.next(&n);
+            }
+        }
+        if !primes.is_empty() {
+            self.insert_lines_before(primes);
+            self.set_counts();
+            self.assign_shapes();
+        }
+    }
+}
+
+#[test]
+fn bands_by_layer() {
+    use super::emit::Emit;
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    G1 E-0.8
+    G1 Z0.4
+    G1 X10
+    G1 E0.8
+    G1 X30 E1
+    G1 Z0.6
+    G1 X10 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let temperature = Tower {
+        param: TowerParam::Temperature,
+        start: 220.0,
+        step: -5.0,
+        bands: Bands::Layers(1),
+        skip: 1,
+    };
+    assert_eq!(gcode.calibration_tower(&temperature).expect("tower"), 2);
+    let out = gcode.emit(&gcode, false);
+    assert!(out.contains("M104 S220") && out.contains("M104 S215"));
+    assert!(!out.contains("M104 S225"));
+    let retraction = Tower {
+        param: TowerParam::Retraction,
+        start: 2.0,
+        step: 1.0,
+        bands: Bands::Height(10.0),
+        skip: 0,
+    };
+    gcode.calibration_tower(&retraction).expect("tower");
+    let e: Vec<f32> = gcode.vertices.values().map(|v| v.to.e).collect();
+    assert!(e.contains(&-2.0) && e.contains(&2.0) && !e.contains(&0.8));
+    // straight back to extruding after the travel, the extra length is primed on its own
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X30 E1
+    G1 E-0.8
+    G1 X10
+    G1 X30 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let total: f32 = gcode.vertices.values().map(|v| v.to.e).sum();
+    gcode.calibration_tower(&retraction).expect("tower");
+    let moves: Vec<_> = gcode
+        .lines
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .map(|v| (v.to.x, v.to.e, v.label))
+        .collect();
+    assert_eq!(moves[3], (30.0, -2.0, Label::Retraction));
+    assert_eq!(moves[5], (10.0, 1.2, Label::DeRetraction));
+    assert_eq!(moves[6], (30.0, 1.0, Label::PlanarExtrustion));
+    let after: f32 = gcode.vertices.values().map(|v| v.to.e).sum();
+    assert!((after - total).abs() < 1e-5);
+}
@@ -1,47 +1,145 @@
-use super::{Id, Parsed};
-use bevy::math::Vec3;
+use super::{Id, Label, Parsed};
+use bevy::math::{Affine3A, EulerRot, Quat, Vec3};
 use core::f32::consts::PI;
+use std::collections::HashSet;
 
 impl Parsed {
-    pub fn rotate(&mut self, vertex: &Id, origin: Vec3, angle_x: f32, angle_y: f32, angle_z: f32) {
-        let v = self.vertices.get_mut(vertex).unwrap();
-        // Translate point back to origin
-        let mut x = v.to.x - origin.x;
-        let mut y = v.to.y - origin.y;
-        let mut z = v.to.z - origin.z;
-
-        // Convert angles from degrees to radians
-        let angle_x = angle_x * PI / 180.0;
-        let angle_y = angle_y * PI / 180.0;
-        let angle_z = angle_z * PI / 180.0;
-
-        // Rotation around X-axis
-        let new_y = y * angle_x.cos() - z * angle_x.sin();
-        let new_z = y * angle_x.sin() + z * angle_x.cos();
-        y = new_y;
-        z = new_z;
-
-        // Rotation around Y-axis
-        let new_x = x * angle_y.cos() + z * angle_y.sin();
-        let new_z = -x * angle_y.sin() + z * angle_y.cos();
-        x = new_x;
-        z = new_z;
-
-        // Rotation around Z-axis
-        let new_x = x * angle_z.cos() - y * angle_z.sin();
-        let new_y = x * angle_z.sin() + y * angle_z.cos();
-        x = new_x;
-        y = new_y;
-
-        // Translate point back
-        v.to.x = x + origin.x;
-        v.to.y = y + origin.y;
-        v.to.z = z + origin.z;
+    // move every selected vertex through `f`. every move that changed length, including
+    // the ones entering and leaving the selection, gets its e scaled to match
+    pub(super) fn move_vertices(&mut self, selection: &HashSet<Id>, f: impl Fn(Vec3) -> Vec3) {
+        // homing always goes to the same place
+        let selected: Vec<Id> = selection
+            .iter()
+            .filter(|id| {
+                self.vertices
+                    .get(id)
+                    .is_some_and(|v| v.label != Label::Home)
+            })
+            .copied()
+            .collect();
+        // a move changes if either of its ends did
+        let mut changed: HashSet<Id> = selected.iter().copied().collect();
+        changed.extend(selected.iter().filter_map(|id| self.lines.next(id)));
+        let lengths: Vec<(Id, f32)> = changed
+            .iter()
+            .filter(|id| self.lines.prev(id).is_some())
+            .map(|id| (*id, self.dist_from_prev(id)))
+            .collect();
+        for id in &selected {
+            let v = self.vertices.get_mut(id).unwrap();
+            let p = f(Vec3::new(v.to.x, v.to.y, v.to.z));
+            (v.to.x, v.to.y, v.to.z) = (p.x, p.y, p.z);
+        }
+        for (id, old) in lengths {
+            let new = self.dist_from_prev(&id);
+            let from = self.vertices[&id].get_from(self);
+            let v = self.vertices.get_mut(&id).unwrap();
+            if v.extrusion_move() && old > f32::EPSILON {
+                v.to.e *= new / old;
+            }
+            // e.g. planar moves that now climb
+            v.label(&from, self.preprint_edge);
+        }
+        self.reindex(&selected);
+    }
+    // move every selected vertex by `matrix` about `pivot`, see `move_vertices`.
+    // with `height` set, moves ending in the selection also follow how much layers were
+    // stretched or squashed
+    pub fn apply_transform(
+        &mut self,
+        selection: &HashSet<Id>,
+        matrix: Affine3A,
+        pivot: Vec3,
+        height: bool,
+    ) {
+        let matrix =
+            Affine3A::from_translation(pivot) * matrix * Affine3A::from_translation(-pivot);
+        self.move_vertices(selection, |p| matrix.transform_point3(p));
+        if !height {
+            return;
+        }
+        // how far apart two layers end up, from how a vertical step is transformed
+        let stretch = matrix.matrix3.z_axis.z.abs();
+        for id in selection {
+            if let Some(v) = self.vertices.get_mut(id) {
+                if v.extrusion_move() {
+                    v.to.e *= stretch;
+                }
+            }
+        }
     }
-    pub fn scale(&mut self, vertex: &Id, origin: Vec3, scale: f32) {
-        let v = self.vertices.get_mut(vertex).unwrap();
-        v.to.x = origin.x + (v.to.x - origin.x) * scale;
-        v.to.y = origin.y + (v.to.y - origin.y) * scale;
-        v.to.z = origin.z + (v.to.z - origin.z) * scale;
+    // angles in degrees, applied about x, then y, then z
+    pub fn rotate(
+        &mut self,
+        selection: &HashSet<Id>,
+        origin: Vec3,
+        angle_x: f32,
+        angle_y: f32,
+        angle_z: f32,
+    ) {
+        let (x, y, z) = (
+            angle_x * PI / 180.0,
+            angle_y * PI / 180.0,
+            angle_z * PI / 180.0,
+        );
+        let rotation = Quat::from_euler(EulerRot::ZYX, z, y, x);
+        self.apply_transform(selection, Affine3A::from_quat(rotation), origin, false);
     }
+    pub fn scale(&mut self, selection: &HashSet<Id>, origin: Vec3, scale: f32) {
+        let matrix = Affine3A::from_scale(Vec3::splat(scale));
+        self.apply_transform(selection, matrix, origin, true);
+    }
+    // flip across the plane through origin with the given normal
+    pub fn mirror(&mut self, selection: &HashSet<Id>, origin: Vec3, normal: Vec3) {
+        let n = normal.normalize();
+        // householder reflection, I - 2nn^T
+        let matrix = Affine3A::from_cols(
+            (Vec3::X - 2.0 * n.x * n).into(),
+            (Vec3::Y - 2.0 * n.y * n).into(),
+            (Vec3::Z - 2.0 * n.z * n).into(),
+            Vec3::ZERO.into(),
+        );
+        self.apply_transform(selection, matrix, origin, false);
+    }
+    // shear x and y in proportion to y and x respectively, e.g. a lean in one direction
+    pub fn skew(&mut self, selection: &HashSet<Id>, origin: Vec3, xy: f32, yx: f32) {
+        let matrix = Affine3A::from_cols(
+            Vec3::new(1.0, yx, 0.0).into(),
+            Vec3::new(xy, 1.0, 0.0).into(),
+            Vec3::Z.into(),
+            Vec3::ZERO.into(),
+        );
+        self.apply_transform(selection, matrix, origin, false);
+    }
+    pub fn translate(&mut self, selection: &HashSet<Id>, offset: Vec3) {
+        let matrix = Affine3A::from_translation(offset);
+        self.apply_transform(selection, matrix, Vec3::ZERO, false);
+    }
+}
+
+#[test]
+fn flow_follows_length() {
+    let gcode = "G28
+    G1 X10 Y10 Z0.2
+    G1 X20 E1
+    G1 X30 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let l = gcode.lines.clone();
+    let e = |gcode: &Parsed, i: usize| gcode.vertices[&l[i]].to.e;
+    // moving the middle vertex stretches the moves on both sides of it
+    let middle = [l[2]].into_iter().collect();
+    gcode.translate(&middle, Vec3::Y * 5.0);
+    let stretched = 125f32.sqrt() / 10.0;
+    assert!((e(&gcode, 2) - stretched).abs() < 1e-4);
+    assert!((e(&gcode, 3) - stretched).abs() < 1e-4);
+    // doubling the height of everything doubles the flow, the lines stay as long
+    let all: HashSet<Id> = l.iter().copied().collect();
+    let tall = Affine3A::from_scale(Vec3::new(1.0, 1.0, 2.0));
+    gcode.apply_transform(&all, tall, Vec3::ZERO, true);
+    assert!((e(&gcode, 3) - stretched * 2.0).abs() < 1e-4);
+    assert_eq!(gcode.vertices[&l[3]].to.z, 0.4);
+    // leaning x by half of y
+    let last = [l[3]].into_iter().collect();
+    gcode.skew(&last, Vec3::ZERO, 0.5, 0.0);
+    assert_eq!(gcode.vertices[&l[3]].to.x, 35.0);
 }
@@ -0,0 +1,527 @@
+use super::contour::{Polygon, CLOSE};
+use super::{Id, Label, Parsed, Pos, Vertex, Word};
+use bevy::math::Vec2;
+use std::ops::Range;
+
+// 2-opt passes over a group before settling for what it has
+const PASSES: usize = 50;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TravelSavings {
+    // mm of travel before and after reordering
+    pub before: f32,
+    pub after: f32,
+}
+
+// lines that keep their place, the shapes between them are reordered on their own
+fn pinned(parsed: &Parsed, id: &Id) -> bool {
+    if let Some(v) = parsed.vertices.get(id) {
+        return v.label == Label::Home;
+    }
+    match parsed.instructions.get(id).map(|ins| &ins.first_word) {
+        // layer markers and macros
+        Some(Word(_, _, Some(_))) => true,
+        Some(Word('T', _, None)) => true,
+        Some(Word('M', n, None)) => matches!(
+            n.round() as i32,
+            // pauses and temperatures
+            0 | 1 | 25 | 104 | 109 | 140 | 141 | 190 | 191 | 600 | 601
+            // state the moves after them run with: extrusion mode, fans, limits,
+            // speed and flow factors, pressure advance and input shaping
+            | 82 | 83 | 106 | 107 | 201..=205 | 220 | 221 | 566 | 572 | 593 | 900
+        ),
+        Some(Word('G', n, None)) => matches!(n.round() as i32, 4 | 20 | 21 | 28 | 90 | 91 | 92),
+        _ => false,
+    }
+}
+
+fn xy(p: &Pos) -> Vec2 {
+    Vec2::new(p.x, p.y)
+}
+
+// the extrusions of one shape, along with the instructions leading up to them
+struct Unit {
+    lines: Vec<Id>,
+    entry: Vec2,
+    exit: Vec2,
+    // a single open run of extrusions that can be printed backwards
+    reversible: bool,
+    // the outline of a loop, walls and the like as opposed to infill
+    outline: Option<Polygon>,
+}
+
+impl Unit {
+    // loops end where they start, so they can be entered from either end as they are
+    fn flippable(&self) -> bool {
+        self.reversible || self.entry.distance(self.exit) < CLOSE
+    }
+    fn entry(&self, flipped: bool) -> Vec2 {
+        if flipped {
+            self.exit
+        } else {
+            self.entry
+        }
+    }
+    fn exit(&self, flipped: bool) -> Vec2 {
+        self.entry(!flipped)
+    }
+}
+
+// a run of lines between pinned ones, split into the moves between shapes and the shapes
+struct Group {
+    range: Range<usize>,
+    // where the nozzle is before the group, and after it if what follows depends on it
+    start: Pos,
+    end: Option<Pos>,
+    // one more than the units, the last one trails the last unit
+    connectors: Vec<Vec<Id>>,
+    units: Vec<Unit>,
+    // `after[a][b]` when unit b has to be printed after unit a
+    after: Vec<Vec<bool>>,
+}
+
+// loops keep their order against open paths, and against loops inside or around them
+fn dependencies(units: &[Unit]) -> Vec<Vec<bool>> {
+    let n = units.len();
+    let mut out = vec![vec![false; n]; n];
+    for b in 0..n {
+        for a in 0..b {
+            out[a][b] = match (&units[a].outline, &units[b].outline) {
+                (Some(pa), Some(pb)) => pa.contains(pb.points[0]) || pb.contains(pa.points[0]),
+                (None, None) => false,
+                _ => true,
+            };
+        }
+    }
+    out
+}
+
+// visiting order starting from `start`, nearest neighbour then 2-opt, never putting a
+// unit before one it comes `after`. returns each unit with whether it's entered from its exit
+fn plan(units: &[Unit], after: &[Vec<bool>], start: Vec2) -> Vec<(usize, bool)> {
+    let mut left: Vec<usize> = (0..units.len()).collect();
+    let mut done = vec![false; units.len()];
+    let mut tour = Vec::with_capacity(units.len());
+    let mut at = start;
+    while !left.is_empty() {
+        let ready = |u: usize| (0..units.len()).all(|a| done[a] || !after[a][u]);
+        let (i, flipped, _) = left
+            .iter()
+            .enumerate()
+            .filter(|(_, u)| ready(**u))
+            .flat_map(|(i, u)| {
+                let unit = &units[*u];
+                let flips: &[bool] = if unit.reversible {
+                    &[false, true]
+                } else {
+                    &[false]
+                };
+                flips
+                    .iter()
+                    .map(move |f| (i, *f, at.distance(unit.entry(*f))))
+            })
+            .min_by(|a, b| a.2.total_cmp(&b.2))
+            .expect("units left");
+        let u = left.swap_remove(i);
+        done[u] = true;
+        at = units[u].exit(flipped);
+        tour.push((u, flipped));
+    }
+    // prefix counts of units that can't be turned around
+    let mut fixed = vec![0];
+    for (u, _) in &tour {
+        fixed.push(fixed[fixed.len() - 1] + usize::from(!units[*u].flippable()));
+    }
+    let n = tour.len();
+    for _ in 0..PASSES {
+        let mut improved = false;
+        for i in 0..n {
+            for j in i + 1..n {
+                // turning the stretch around swaps every pair in it
+                let (b, _) = tour[j];
+                if fixed[j + 1] != fixed[i] || tour[i..j].iter().any(|(a, _)| after[*a][b]) {
+                    break;
+                }
+                let (a, fa) = tour[i];
+                let (b, fb) = tour[j];
+                let before = if i == 0 {
+                    start
+                } else {
+                    let (p, fp) = tour[i - 1];
+                    units[p].exit(fp)
+                };
+                let after = tour.get(j + 1).map(|(q, fq)| units[*q].entry(*fq));
+                let old = before.distance(units[a].entry(fa))
+                    + after.map_or(0.0, |p| units[b].exit(fb).distance(p));
+                let new = before.distance(units[b].exit(fb))
+                    + after.map_or(0.0, |p| units[a].entry(fa).distance(p));
+                if new < old - 1e-3 {
+                    tour[i..=j].reverse();
+                    for (_, flipped) in &mut tour[i..=j] {
+                        *flipped = !*flipped;
+                    }
+                    improved = true;
+                }
+            }
+        }
+        if !improved {
+            break;
+        }
+    }
+    tour
+}
+
+fn cost(units: &[Unit], start: Vec2, tour: &[(usize, bool)]) -> f32 {
+    let mut at = start;
+    let mut out = 0.0;
+    for (u, flipped) in tour {
+        out += at.distance(units[*u].entry(*flipped));
+        at = units[*u].exit(*flipped);
+    }
+    out
+}
+
+impl Parsed {
+    // xy distance covered by moves that don't extrude
+    pub fn travel_distance(&self) -> f32 {
+        self.vertices
+            .values()
+            .filter(|v| !v.extrusion_move() && v.label != Label::Home)
+            .map(|v| xy(&v.to).distance(xy(&v.get_from(self))))
+            .sum()
+    }
+    // reorder the shapes of every layer to cut down on travel, printing open paths
+    // backwards where that helps. lines like tool changes and temperatures stay put,
+    // the moves between shapes are pointed at their new neighbours
+    pub fn optimize_travel(&mut self) -> TravelSavings {
+        let before = self.travel_distance();
+        let groups = self.travel_groups();
+        let mut edits = Vec::new();
+        for mut group in groups {
+            let start = xy(&group.start);
+            let tour = plan(&group.units, &group.after, start);
+            let unchanged: Vec<(usize, bool)> =
+                (0..group.units.len()).map(|u| (u, false)).collect();
+            if cost(&group.units, start, &tour) >= cost(&group.units, start, &unchanged) - 1e-3 {
+                continue;
+            }
+            let mut lines = Vec::with_capacity(group.range.len() + group.units.len());
+            let mut at = group.start;
+            for (i, (u, flipped)) in tour.iter().enumerate() {
+                if *flipped && group.units[*u].reversible {
+                    self.reverse_unit(&mut group.units[*u]);
+                }
+                let unit = &group.units[*u];
+                let mut connector = std::mem::take(&mut group.connectors[i]);
+                self.reconnect(&mut connector, &at, Some(unit.entry));
+                lines.extend(connector);
+                lines.extend(&unit.lines);
+                let last = unit.lines.iter().rev().find_map(|id| self.vertices.get(id));
+                at = last.map_or(at, |v| v.to);
+            }
+            let mut trailing = group.connectors.pop().unwrap_or_default();
+            self.reconnect(&mut trailing, &at, group.end.map(|p| xy(&p)));
+            lines.extend(trailing);
+            edits.push((group.range, lines));
+        }
+        if !edits.is_empty() {
+            let ids: Vec<Id> = edits.iter().flat_map(|(_, ids)| ids).copied().collect();
+            self.replace_lines(edits);
+            // labels can only be judged once every move starts where it ends up
+            for id in &ids {
+                let Some(from) = self.vertices.get(id).map(|v| v.get_from(self)) else {
+                    continue;
+                };
+                if let Some(v) = self.vertices.get_mut(id) {
+                    v.label(&from, self.preprint_edge);
+                }
+            }
+        }
+        TravelSavings {
+            before,
+            after: self.travel_distance(),
+        }
+    }
+    // split every layer at its pinned lines, then into shapes and the moves between them
+    fn travel_groups(&self) -> Vec<Group> {
+        let mut groups = Vec::new();
+        for layer in self.layers() {
+            let mut start = layer.lines.start;
+            for pos in layer.lines.clone() {
+                if pinned(self, &self.lines[pos]) {
+                    groups.extend(self.travel_group(start..pos));
+                    start = pos + 1;
+                }
+            }
+            groups.extend(self.travel_group(start..layer.lines.end));
+        }
+        groups
+    }
+    fn travel_group(&self, range: Range<usize>) -> Option<Group> {
+        let ids = &self.lines[range.clone()];
+        let first = ids.iter().find(|id| self.vertices.contains_key(id))?;
+        let last = ids.iter().rev().find(|id| self.vertices.contains_key(id))?;
+        let mut group = Group {
+            start: self.vertices[first].get_from(self),
+            end: None,
+            range,
+            connectors: Vec::new(),
+            units: Vec::new(),
+            after: Vec::new(),
+        };
+        // lines since the last extrusion of the current unit
+        let mut tail: Vec<Id> = Vec::new();
+        let mut current: Option<(usize, Vec<Id>)> = None;
+        for id in ids {
+            let shape = match self.vertices.get(id) {
+                Some(v) if v.extrusion_move() => self.shape_of.get(id).copied(),
+                _ => None,
+            };
+            let Some(shape) = shape else {
+                tail.push(*id);
+                continue;
+            };
+            match &mut current {
+                Some((s, lines)) if *s == shape => {
+                    lines.append(&mut tail);
+                    lines.push(*id);
+                }
+                _ => {
+                    if let Some((_, mut lines)) = current.take() {
+                        lines.extend(self.take_wipes(&mut tail));
+                        group.units.push(self.unit(lines));
+                    }
+                    // moves go between the shapes, instructions stay with the shape after them
+                    let (mut lines, connector): (Vec<Id>, Vec<Id>) = std::mem::take(&mut tail)
+                        .into_iter()
+                        .partition(|id| self.instructions.contains_key(id));
+                    group.connectors.push(connector);
+                    lines.push(*id);
+                    current = Some((shape, lines));
+                }
+            }
+        }
+        if let Some((_, mut lines)) = current {
+            lines.extend(self.take_wipes(&mut tail));
+            group.units.push(self.unit(lines));
+        }
+        // the travel out of the group, or an extrusion right after it, expect the old end
+        let travels = tail.iter().any(|id| {
+            self.vertices
+                .get(id)
+                .is_some_and(|v| v.label == Label::TravelMove)
+        });
+        let extrudes = self
+            .lines
+            .next(last)
+            .is_some_and(|id| self.vertices[&id].extrusion_move());
+        if travels || extrudes {
+            group.end = Some(self.vertices[last].to);
+        }
+        group.connectors.push(tail);
+        group.after = dependencies(&group.units);
+        (group.units.len() > 1).then_some(group)
+    }
+    // wipes after a shape, up to the travel away from it, and whatever comes before them
+    fn take_wipes(&self, tail: &mut Vec<Id>) -> Vec<Id> {
+        let label = |id: &Id| self.vertices.get(id).map(|v| v.label);
+        let travel = tail
+            .iter()
+            .position(|id| label(id) == Some(Label::TravelMove))
+            .unwrap_or(tail.len());
+        match tail[..travel]
+            .iter()
+            .rposition(|id| label(id) == Some(Label::Wipe))
+        {
+            Some(last) => tail.drain(..=last).collect(),
+            None => Vec::new(),
+        }
+    }
+    fn unit(&self, lines: Vec<Id>) -> Unit {
+        let moves: Vec<&Vertex> = lines
+            .iter()
+            .filter_map(|id| self.vertices.get(id))
+            .collect();
+        let entry = xy(&moves[0].get_from(self));
+        let exit = xy(&moves[moves.len() - 1].to);
+        let reversible = moves.iter().all(|v| v.extrusion_move())
+            && moves.iter().all(|v| v.to.z == moves[0].to.z)
+            && lines
+                .last()
+                .is_some_and(|id| self.vertices.contains_key(id))
+            && entry.distance(exit) >= CLOSE;
+        // a wipe after a loop doesn't make it any less of a loop
+        let points: Vec<Vec2> = std::iter::once(entry)
+            .chain(
+                moves
+                    .iter()
+                    .filter(|v| v.extrusion_move())
+                    .map(|v| xy(&v.to)),
+            )
+            .collect();
+        let closed = points.len() > 3 && entry.distance(points[points.len() - 1]) < CLOSE;
+        Unit {
+            lines,
+            entry,
+            exit,
+            reversible,
+            outline: closed.then(|| Polygon::build(0, &points)),
+        }
+    }
+    // print a run of extrusions the other way, every move keeps its own e and feedrate
+    fn reverse_unit(&mut self, unit: &mut Unit) {
+        let split = unit
+            .lines
+            .iter()
+            .position(|id| self.vertices.contains_key(id))
+            .unwrap_or(unit.lines.len());
+        let moves = unit.lines.split_off(split);
+        let from: Vec<Pos> = moves
+            .iter()
+            .map(|id| self.vertices[id].get_from(self))
+            .collect();
+        for (id, from) in moves.iter().zip(from) {
+            if let Some(v) = self.vertices.get_mut(id) {
+                v.to.x = from.x;
+                v.to.y = from.y;
+                v.to.z = from.z;
+            }
+        }
+        unit.lines.extend(moves.into_iter().rev());
+        std::mem::swap(&mut unit.entry, &mut unit.exit);
+    }
+    // moves between two shapes. the ones before the travel stay where the last shape ended,
+    // the travel and the ones after it go to where the next one starts, if there is one
+    fn reconnect(&mut self, connector: &mut Vec<Id>, from: &Pos, to: Option<Vec2>) {
+        let to = to.unwrap_or(xy(from));
+        let travel = connector.iter().rposition(|id| {
+            self.vertices
+                .get(id)
+                .is_some_and(|v| v.label == Label::TravelMove)
+        });
+        let travel = match travel {
+            Some(i) => i,
+            None if xy(from).distance(to) < 1e-4 => connector.len(),
+            None => {
+                // arrive after lifting and retracting, but before lowering and deretracting
+                let i = connector
+                    .iter()
+                    .position(|id| {
+                        self.vertices.get(id).is_some_and(|v| {
+                            v.label == Label::LowerZ || v.label == Label::DeRetraction
+                        })
+                    })
+                    .unwrap_or(connector.len());
+                let prev = connector[..i]
+                    .iter()
+                    .rev()
+                    .find_map(|id| self.vertices.get(id))
+                    .map_or(*from, |v| v.to);
+                let travel = Vertex {
+                    id: self.id_counter.get(),
+                    count: 0,
+                    label: Label::TravelMove,
+                    to: Pos {
+                        x: to.x,
+                        y: to.y,
+                        e: 0.0,
+                        ..prev
+                    },
+                };
+                self.vertices.insert(travel.id, travel);
+                connector.insert(i, travel.id);
+                i
+            }
+        };
+        for (i, id) in connector.iter().enumerate() {
+            if let Some(v) = self.vertices.get_mut(id) {
+                let p = if i < travel { xy(from) } else { to };
+                v.to.x = p.x;
+                v.to.y = p.y;
+            }
+        }
+    }
+}
+
+#[test]
+fn reorder_and_reverse() {
+    // three lines printed in a poor order, the middle one pointing the wrong way,
+    // and a temperature change that has to stay between the first two
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 X20 E1
+    M104 S200
+    G1 X50 Y10
+    G1 X60 E1
+    G1 E-1
+    G1 X40 Y10
+    G1 X30 E2
+    G1 E-1
+    G1 X40 Y15
+    G1 X30 E2";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let total_e = |gcode: &Parsed| gcode.vertices.values().map(|v| v.to.e).sum::<f32>();
+    let e = total_e(&gcode);
+    let savings = gcode.optimize_travel();
+    assert!(savings.after < savings.before);
+    assert_eq!(total_e(&gcode), e);
+    assert_eq!(gcode.lines.len(), 12);
+    // the temperature change still follows the first line
+    assert!(gcode.instructions.contains_key(&gcode.lines[3]));
+    let starts: Vec<(f32, f32)> = gcode
+        .lines
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .filter(|v| v.extrusion_move())
+        .map(|v| {
+            let from = v.get_from(&gcode);
+            (from.x, from.y)
+        })
+        .collect();
+    assert_eq!(starts[1..], [(30.0, 10.0), (40.0, 15.0), (50.0, 10.0)]);
+    // two loops and some infill inside the second. the loops can swap, but the infill
+    // stays after both of them, and the wipe stays with its loop
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F1200
+    G1 E-0.5
+    G1 X50 Y50
+    G1 E0.5
+    G1 X60 E1
+    G1 Y60 E1
+    G1 X50 E1
+    G1 Y50 E1
+    G1 E-0.5
+    G1 X10 Y10
+    G1 E0.5
+    G1 X20 E1
+    G1 Y20 E1
+    G1 X10 E1
+    G1 Y10 E1
+    G1 X14 E-0.3
+    G1 E-0.2
+    G1 X12 Y15
+    G1 E0.5
+    G1 X18 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let savings = gcode.optimize_travel();
+    assert!(savings.after < savings.before);
+    let moves: Vec<&Vertex> = gcode
+        .lines
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .collect();
+    let firsts: Vec<(f32, f32)> = moves
+        .windows(2)
+        .filter(|w| !w[0].extrusion_move() && w[1].extrusion_move())
+        .map(|w| (w[1].to.x, w[1].to.y))
+        .collect();
+    // the infill is printed from its nearer end
+    assert_eq!(firsts, [(20.0, 10.0), (60.0, 50.0), (12.0, 15.0)]);
+    let wipe = moves.iter().position(|v| v.label == Label::Wipe).unwrap();
+    assert_eq!((moves[wipe - 1].to.x, moves[wipe - 1].to.y), (10.0, 10.0));
+    assert_eq!((moves[wipe].to.x, moves[wipe].to.y), (14.0, 10.0));
+    // state the moves after it depend on isn't moved around
+    let gcode = Parsed::build("G28\nM220 S50\nM204 P500\nM400", true).expect("failed to parse");
+    let pins: Vec<bool> = gcode.lines.iter().map(|id| pinned(&gcode, id)).collect();
+    assert_eq!(pins, [true, true, true, false]);
+}
@@ -0,0 +1,106 @@
+use super::{Id, Label, Parsed};
+use std::collections::HashSet;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Adjust {
+    // e per mm for flow, mm/min for feedrate
+    Set,
+    Multiply,
+}
+
+impl Parsed {
+    // selected moves in line order with how far along the selection their middle is, 0 to 1
+    fn along_selection(
+        &self,
+        selection: &HashSet<Id>,
+        moves: impl Fn(&Id) -> bool,
+    ) -> Vec<(Id, f32)> {
+        let ids: Vec<(Id, f32)> = self
+            .lines
+            .iter()
+            .filter(|id| selection.contains(id) && self.lines.prev(id).is_some() && moves(id))
+            .map(|id| (*id, self.dist_from_prev(id)))
+            .collect();
+        let total: f32 = ids.iter().map(|(_, len)| len).sum();
+        let mut done = 0.0;
+        ids.into_iter()
+            .map(|(id, len)| {
+                let t = if total > f32::EPSILON {
+                    (done + len / 2.0) / total
+                } else {
+                    0.0
+                };
+                done += len;
+                (id, t)
+            })
+            .collect()
+    }
+    // change the flow of the selected extrusion moves, ramping from `start` at the first to
+    // `end` at the last. a constant change has the same start and end
+    pub fn adjust_flow(&mut self, selection: &HashSet<Id>, adjust: Adjust, start: f32, end: f32) {
+        let moves = self.along_selection(selection, |id| self.vertices[id].extrusion_move());
+        for (id, t) in moves {
+            let value = start + (end - start) * t;
+            let len = self.dist_from_prev(&id);
+            let v = self.vertices.get_mut(&id).unwrap();
+            match adjust {
+                Adjust::Set => v.to.e = value * len,
+                Adjust::Multiply => v.to.e *= value,
+            }
+        }
+    }
+    // change the feedrate of the selected moves the same way as `adjust_flow`. retractions
+    // keep their speed, and ramps are rounded to whole mm/min so they don't write F on
+    // every line
+    pub fn adjust_feedrate(
+        &mut self,
+        selection: &HashSet<Id>,
+        adjust: Adjust,
+        start: f32,
+        end: f32,
+    ) {
+        let moves = self.along_selection(selection, |id| {
+            self.vertices[id].label != Label::Home && self.dist_from_prev(id) > f32::EPSILON
+        });
+        let ramped = start != end;
+        let mut changed = Vec::new();
+        for (id, t) in moves {
+            let value = start + (end - start) * t;
+            let v = self.vertices.get_mut(&id).unwrap();
+            let f = match adjust {
+                Adjust::Set => value,
+                Adjust::Multiply => v.to.f * value,
+            };
+            v.to.f = if ramped { f.round() } else { f }.max(1.0);
+            changed.push(id);
+            changed.extend(self.lines.next(&id));
+        }
+        // moves in place after a changed one may now only change the feedrate
+        for id in changed {
+            let from = self.vertices[&id].get_from(self);
+            if let Some(v) = self.vertices.get_mut(&id) {
+                v.label(&from, self.preprint_edge);
+            }
+        }
+    }
+}
+
+#[test]
+fn ramp_flow_and_speed() {
+    let gcode = "G28
+    G1 X10 Y10 Z0.2 F3000
+    G1 X20 E1 F1200
+    G1 X30 E1
+    G1 X40 E1";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let l = gcode.lines.clone();
+    let middle: HashSet<Id> = [l[2], l[3]].into_iter().collect();
+    gcode.adjust_flow(&middle, Adjust::Multiply, 1.0, 2.0);
+    let e: Vec<f32> = l[2..].iter().map(|id| gcode.vertices[id].to.e).collect();
+    assert_eq!(e, vec![1.25, 1.75, 1.0]);
+    gcode.adjust_flow(&middle, Adjust::Set, 0.05, 0.05);
+    assert_eq!(gcode.vertices[&l[3]].to.e, 0.5);
+    gcode.adjust_feedrate(&middle, Adjust::Set, 600.0, 600.0);
+    let f: Vec<f32> = l[1..].iter().map(|id| gcode.vertices[id].to.f).collect();
+    assert_eq!(f, vec![3000.0, 600.0, 600.0, 1200.0]);
+}
@@ -0,0 +1,123 @@
+use super::{Id, Label, Parsed, Word};
+use std::collections::{BTreeMap, HashMap};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Filament {
+    // mm
+    pub diameter: f32,
+    // g/cm^3
+    pub density: f32,
+    // price per kg
+    pub price: f32,
+}
+
+impl Default for Filament {
+    fn default() -> Self {
+        Filament {
+            diameter: 1.75,
+            density: 1.24,
+            price: 20.0,
+        }
+    }
+}
+
+impl Filament {
+    // mm^3 of plastic in a mm of filament
+    pub fn area(&self) -> f32 {
+        std::f32::consts::PI * (self.diameter / 2.0).powi(2)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    // mm of filament
+    pub length: f32,
+    // mm^3
+    pub volume: f32,
+    // g
+    pub mass: f32,
+    pub cost: f32,
+}
+
+impl Usage {
+    fn add(&mut self, length: f32, filament: &Filament) {
+        let volume = length * filament.area();
+        // mm^3 -> cm^3 for density
+        let mass = volume / 1000.0 * filament.density;
+        self.length += length;
+        self.volume += volume;
+        self.mass += mass;
+        self.cost += mass / 1000.0 * filament.price;
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsageReport {
+    pub total: Usage,
+    // (layer z, usage) in print order
+    pub layers: Vec<(f32, Usage)>,
+    pub shapes: HashMap<Id, Usage>,
+    pub labels: HashMap<Label, Usage>,
+    // tool number -> usage, moves before any tool change count toward T0
+    pub tools: BTreeMap<u32, Usage>,
+}
+
+impl Parsed {
+    // net filament pushed per category, retractions subtract from the category they're in.
+    // this assumes relative e like the rest of the analyzer
+    pub fn filament_usage(&self, filament: &Filament) -> UsageReport {
+        let mut out = UsageReport::default();
+        let mut tool = 0;
+        let mut tool_of = HashMap::new();
+        for line in &self.lines {
+            if let Some(v) = self.vertices.get(line) {
+                if v.to.e != 0.0 {
+                    out.total.add(v.to.e, filament);
+                    out.labels.entry(v.label).or_default().add(v.to.e, filament);
+                    out.tools.entry(tool).or_default().add(v.to.e, filament);
+                    tool_of.insert(v.id, tool);
+                }
+            } else if let Some(ins) = self.instructions.get(line) {
+                if let Word('T', num, None) = ins.first_word {
+                    tool = num.round() as u32;
+                }
+            }
+        }
+        for shape in &self.shapes {
+            let mut usage = Usage::default();
+            for line in &shape.lines {
+                if let Some(v) = self.vertices.get(line) {
+                    usage.add(v.to.e, filament);
+                }
+            }
+            if usage == Usage::default() {
+                continue;
+            }
+            out.shapes.insert(shape.id, usage);
+        }
+        for layer in self.layers() {
+            let mut usage = Usage::default();
+            for line in &self.lines[layer.lines.clone()] {
+                if let Some(v) = self.vertices.get(line) {
+                    usage.add(v.to.e, filament);
+                }
+            }
+            out.layers.push((layer.z, usage));
+        }
+        out
+    }
+}
+
+#[test]
+fn usage_by_tool() {
+    let gcode = "G28\nG1 X10 Y10 Z0.2\nG1 X20 E2\nT1\nG1 X30 E3\nG1 E-1\n";
+    let gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let filament = Filament::default();
+    let report = gcode.filament_usage(&filament);
+    assert!((report.total.length - 4.0).abs() < 1e-5);
+    assert!((report.tools[&0].length - 2.0).abs() < 1e-5);
+    assert!((report.tools[&1].length - 2.0).abs() < 1e-5);
+    // 1.75mm pla is about 3g per meter
+    let per_meter = report.total.mass / report.total.length * 1000.0;
+    assert!((per_meter - 2.98).abs() < 0.01);
+}
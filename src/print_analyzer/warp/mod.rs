@@ -0,0 +1,177 @@
+use super::{Id, Parsed};
+use bevy::math::{Vec2, Vec3};
+use core::f32::consts::PI;
+use std::collections::HashSet;
+
+// heights sampled on a regular grid spanning min to max, row by row from min y
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeightMap {
+    pub min: Vec2,
+    pub max: Vec2,
+    cols: usize,
+    rows: usize,
+    values: Vec<f32>,
+}
+
+impl HeightMap {
+    pub fn new(
+        cols: usize,
+        rows: usize,
+        values: Vec<f32>,
+        min: Vec2,
+        max: Vec2,
+    ) -> Result<HeightMap, Box<dyn std::error::Error>> {
+        if cols == 0 || rows == 0 || values.len() != cols * rows {
+            return Err(format!(
+                "{} values don't make a {}x{} grid",
+                values.len(),
+                cols,
+                rows
+            )
+            .into());
+        }
+        Ok(HeightMap {
+            min,
+            max,
+            cols,
+            rows,
+            values,
+        })
+    }
+    // one row per line, values split by commas or whitespace
+    pub fn from_csv(
+        text: &str,
+        min: Vec2,
+        max: Vec2,
+    ) -> Result<HeightMap, Box<dyn std::error::Error>> {
+        let mut rows = Vec::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let row = line
+                .split(|c: char| c == ',' || c.is_whitespace())
+                .filter(|s| !s.is_empty())
+                .map(|s| s.parse::<f32>())
+                .collect::<Result<Vec<f32>, _>>()?;
+            rows.push(row);
+        }
+        let cols = rows.first().map_or(0, Vec::len);
+        if rows.iter().any(|r| r.len() != cols) {
+            return Err("rows of the height map differ in length".into());
+        }
+        let count = rows.len();
+        Self::new(cols, count, rows.concat(), min, max)
+    }
+    fn at(&self, col: usize, row: usize) -> f32 {
+        self.values[row * self.cols + col]
+    }
+    // bilinear between the nearest grid points, held flat past the edges
+    pub fn sample(&self, p: Vec2) -> f32 {
+        let span = (self.max - self.min).max(Vec2::splat(f32::EPSILON));
+        let cells = Vec2::new((self.cols - 1) as f32, (self.rows - 1) as f32);
+        let g = ((p - self.min) / span * cells).clamp(Vec2::ZERO, cells);
+        let (c0, r0) = (g.x.floor() as usize, g.y.floor() as usize);
+        let (c1, r1) = ((c0 + 1).min(self.cols - 1), (r0 + 1).min(self.rows - 1));
+        let (tx, ty) = (g.x - c0 as f32, g.y - r0 as f32);
+        let bottom = self.at(c0, r0) * (1.0 - tx) + self.at(c1, r0) * tx;
+        let top = self.at(c0, r1) * (1.0 - tx) + self.at(c1, r1) * tx;
+        bottom * (1.0 - ty) + top * ty
+    }
+}
+
+// z offset in mm for a point on the bed
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeightField {
+    Map(HeightMap),
+    // waves travelling along `direction`
+    Sine {
+        amplitude: f32,
+        wavelength: f32,
+        direction: Vec2,
+    },
+    // a bump falling away to nothing at `radius`
+    Dome {
+        center: Vec2,
+        radius: f32,
+        height: f32,
+    },
+    // rises by `slope` mm per mm away from `origin`
+    Ramp {
+        origin: Vec2,
+        slope: Vec2,
+    },
+}
+
+impl HeightField {
+    pub fn height(&self, p: Vec2) -> f32 {
+        match self {
+            HeightField::Map(map) => map.sample(p),
+            HeightField::Sine {
+                amplitude,
+                wavelength,
+                direction,
+            } => {
+                let along = p.dot(direction.normalize_or_zero());
+                amplitude * (2.0 * PI * along / wavelength).sin()
+            }
+            HeightField::Dome {
+                center,
+                radius,
+                height,
+            } => {
+                let r = p.distance(*center) / radius;
+                if r < 1.0 {
+                    height * (1.0 - r * r)
+                } else {
+                    0.0
+                }
+            }
+            HeightField::Ramp { origin, slope } => (p - *origin).dot(*slope),
+        }
+    }
+}
+
+impl Parsed {
+    // raise or lower the selection by the height field, after splitting moves longer than
+    // `max_len` so the surface comes out smooth. e follows the new length of every move
+    pub fn displace_z(&mut self, selection: &HashSet<Id>, field: &HeightField, max_len: f32) {
+        let ids: Vec<Id> = selection.iter().copied().collect();
+        let mut selection = selection.clone();
+        selection.extend(self.subdivide_to(&ids, max_len));
+        self.move_vertices(&selection, |p| {
+            p + Vec3::Z * field.height(Vec2::new(p.x, p.y))
+        });
+        self.assign_shapes();
+    }
+}
+
+#[test]
+fn dome_over_a_line() {
+    use super::Label;
+    let gcode = "G28
+    G1 X10 Y10 Z0.2
+    G1 X30 E2";
+    let mut gcode = Parsed::build(gcode, true).expect("failed to parse");
+    let all: HashSet<Id> = gcode.lines.iter().copied().collect();
+    let dome = HeightField::Dome {
+        center: Vec2::new(20.0, 10.0),
+        radius: 10.0,
+        height: 1.0,
+    };
+    gcode.displace_z(&all, &dome, 2.0);
+    let moves: Vec<_> = gcode
+        .lines
+        .iter()
+        .filter_map(|id| gcode.vertices.get(id))
+        .collect();
+    // the 20mm line is now 10 moves, peaking in the middle
+    assert_eq!(moves.len(), 12);
+    assert!((moves[6].to.z - 1.2).abs() < 1e-4);
+    assert_eq!(moves[6].label, Label::NonPlanarExtrusion);
+    let e: f32 = moves.iter().map(|v| v.to.e).sum();
+    let length: f32 = moves[2..]
+        .iter()
+        .map(|v| v.to.dist(&v.get_from(&gcode)))
+        .sum();
+    assert!(e > 2.0 && (e / length - 0.1).abs() < 1e-4);
+    let map = HeightMap::from_csv("0,1\n2,3", Vec2::ZERO, Vec2::ONE).expect("bad csv");
+    assert_eq!(map.sample(Vec2::splat(0.5)), 1.5);
+}
@@ -0,0 +1,328 @@
+use bevy::math::{Affine3A, Vec3, Vec3A};
+use std::collections::HashMap;
+use std::io::Read;
+
+// triangle soup read from an stl or the mesh objects of a 3mf
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RefMesh {
+    pub triangles: Vec<[Vec3; 3]>,
+}
+
+impl RefMesh {
+    pub fn read(path: &str) -> Result<RefMesh, Box<dyn std::error::Error>> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".3mf") {
+            read_3mf(path)
+        } else if lower.ends_with(".stl") {
+            Ok(parse_stl(&std::fs::read(path)?)?)
+        } else {
+            Err(format!("unsupported reference model format: {}", path).into())
+        }
+    }
+    pub fn bounds(&self) -> (Vec3, Vec3) {
+        let mut min = Vec3::splat(f32::INFINITY);
+        let mut max = Vec3::splat(f32::NEG_INFINITY);
+        for tri in &self.triangles {
+            for p in tri {
+                min = min.min(*p);
+                max = max.max(*p);
+            }
+        }
+        (min, max)
+    }
+    // offset that centers the mesh in xy over the extrusion bounds and puts
+    // the top of the mesh level with the top layer of the toolpath
+    pub fn align_to(&self, min: Vec3, max: Vec3) -> Vec3 {
+        let (m_min, m_max) = self.bounds();
+        let center = (min + max) / 2.0;
+        let m_center = (m_min + m_max) / 2.0;
+        Vec3::new(
+            center.x - m_center.x,
+            center.y - m_center.y,
+            max.z - m_max.z,
+        )
+    }
+    // translate by offset and clip every triangle to z_min..z_max, re-triangulating the cut faces
+    pub fn clip_z(&self, offset: Vec3, z_min: f32, z_max: f32) -> Vec<[Vec3; 3]> {
+        let mut out = Vec::new();
+        for tri in &self.triangles {
+            let poly: Vec<Vec3> = tri.iter().map(|p| *p + offset).collect();
+            if poly.iter().all(|p| p.z >= z_min && p.z <= z_max) {
+                out.push([poly[0], poly[1], poly[2]]);
+                continue;
+            }
+            let poly = clip_plane(&poly, |p| p.z - z_min);
+            let poly = clip_plane(&poly, |p| z_max - p.z);
+            for i in 1..poly.len().saturating_sub(1) {
+                out.push([poly[0], poly[i], poly[i + 1]]);
+            }
+        }
+        out
+    }
+}
+
+// sutherland-hodgman against a single plane, keeping points where side(p) >= 0
+fn clip_plane(poly: &[Vec3], side: impl Fn(&Vec3) -> f32) -> Vec<Vec3> {
+    let mut out = Vec::new();
+    for (i, curr) in poly.iter().enumerate() {
+        let next = &poly[(i + 1) % poly.len()];
+        let (dc, dn) = (side(curr), side(next));
+        if dc >= 0.0 {
+            out.push(*curr);
+        }
+        if (dc >= 0.0) != (dn >= 0.0) {
+            let t = dc / (dc - dn);
+            out.push(curr.lerp(*next, t));
+        }
+    }
+    out
+}
+
+pub fn parse_stl(bytes: &[u8]) -> Result<RefMesh, Box<dyn std::error::Error>> {
+    // binary stls may also start with "solid", so check the size first
+    if bytes.len() >= 84 {
+        let count = u32::from_le_bytes([bytes[80], bytes[81], bytes[82], bytes[83]]) as usize;
+        if bytes.len() == 84 + count * 50 {
+            let mut triangles = Vec::with_capacity(count);
+            for i in 0..count {
+                // skip the 12 byte facet normal
+                let start = 84 + i * 50 + 12;
+                let mut tri = [Vec3::ZERO; 3];
+                for (j, p) in tri.iter_mut().enumerate() {
+                    let mut v = [0.0; 3];
+                    for (k, c) in v.iter_mut().enumerate() {
+                        let b = start + j * 12 + k * 4;
+                        *c = f32::from_le_bytes([
+                            bytes[b],
+                            bytes[b + 1],
+                            bytes[b + 2],
+                            bytes[b + 3],
+                        ]);
+                    }
+                    *p = Vec3::from_array(v);
+                }
+                triangles.push(tri);
+            }
+            return Ok(RefMesh { triangles });
+        }
+    }
+    let text = std::str::from_utf8(bytes)?;
+    let mut triangles = Vec::new();
+    let mut tri = Vec::new();
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some("vertex") {
+            continue;
+        }
+        let mut v = [0.0; 3];
+        for c in v.iter_mut() {
+            *c = words.next().ok_or("truncated stl vertex")?.parse::<f32>()?;
+        }
+        tri.push(Vec3::from_array(v));
+        if tri.len() == 3 {
+            triangles.push([tri[0], tri[1], tri[2]]);
+            tri.clear();
+        }
+    }
+    if triangles.is_empty() {
+        return Err("no facets found in stl".into());
+    }
+    Ok(RefMesh { triangles })
+}
+
+fn read_3mf(path: &str) -> Result<RefMesh, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+    let mut models = HashMap::new();
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if !file.name().ends_with(".model") {
+            continue;
+        }
+        let mut text = String::new();
+        file.read_to_string(&mut text)?;
+        models.insert(format!("/{}", file.name().trim_start_matches('/')), text);
+    }
+    parse_3mf_models(&models)
+}
+
+#[derive(Default)]
+struct Object {
+    vertices: Vec<Vec3>,
+    triangles: Vec<[usize; 3]>,
+    // (model path, object id, transform)
+    components: Vec<(String, String, Affine3A)>,
+}
+
+// models maps the archive path of each .model part to its xml
+pub fn parse_3mf_models(
+    models: &HashMap<String, String>,
+) -> Result<RefMesh, Box<dyn std::error::Error>> {
+    let root = "/3D/3dmodel.model";
+    let text = models.get(root).ok_or("3mf has no 3D/3dmodel.model")?;
+    let mut objects = HashMap::new();
+    for (path, text) in models {
+        for (id, object) in parse_objects(path, text) {
+            objects.insert((path.clone(), id), object);
+        }
+    }
+    let mut out = RefMesh::default();
+    for item in tags(text, "item") {
+        let id = attr(item, "objectid").ok_or("3mf build item without objectid")?;
+        let transform = attr(item, "transform").map_or(Affine3A::IDENTITY, parse_transform);
+        let path = attr(item, "p:path").unwrap_or(root);
+        flatten(&objects, path, id, transform, &mut out.triangles, 0)?;
+    }
+    if out.triangles.is_empty() {
+        return Err("no mesh found in 3mf".into());
+    }
+    Ok(out)
+}
+
+fn flatten(
+    objects: &HashMap<(String, String), Object>,
+    path: &str,
+    id: &str,
+    transform: Affine3A,
+    out: &mut Vec<[Vec3; 3]>,
+    depth: u32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if depth > 16 {
+        return Err("3mf component nesting too deep".into());
+    }
+    let object = objects
+        .get(&(path.to_string(), id.to_string()))
+        .ok_or(format!("3mf object {} not found in {}", id, path))?;
+    for [a, b, c] in &object.triangles {
+        let p = |i: usize| -> Result<Vec3, String> {
+            let v = object
+                .vertices
+                .get(i)
+                .ok_or("3mf triangle index out of range")?;
+            Ok(transform.transform_point3(*v))
+        };
+        out.push([p(*a)?, p(*b)?, p(*c)?]);
+    }
+    for (c_path, c_id, c_transform) in &object.components {
+        flatten(
+            objects,
+            c_path,
+            c_id,
+            transform * *c_transform,
+            out,
+            depth + 1,
+        )?;
+    }
+    Ok(())
+}
+
+fn parse_objects(path: &str, text: &str) -> Vec<(String, Object)> {
+    let mut out = Vec::new();
+    for block in text.split("<object").skip(1) {
+        let block = block.split("</object>").next().unwrap_or("");
+        let Some(id) = attr(block, "id") else {
+            continue;
+        };
+        let mut object = Object::default();
+        for v in tags(block, "vertex") {
+            let c = |n| {
+                attr(v, n)
+                    .and_then(|s| s.parse::<f32>().ok())
+                    .unwrap_or(0.0)
+            };
+            object.vertices.push(Vec3::new(c("x"), c("y"), c("z")));
+        }
+        for t in tags(block, "triangle") {
+            let i = |n| attr(t, n).and_then(|s| s.parse::<usize>().ok());
+            if let (Some(a), Some(b), Some(c)) = (i("v1"), i("v2"), i("v3")) {
+                object.triangles.push([a, b, c]);
+            }
+        }
+        for c in tags(block, "component") {
+            if let Some(c_id) = attr(c, "objectid") {
+                let transform = attr(c, "transform").map_or(Affine3A::IDENTITY, parse_transform);
+                let c_path = attr(c, "p:path").unwrap_or(path);
+                object
+                    .components
+                    .push((c_path.to_string(), c_id.to_string(), transform));
+            }
+        }
+        out.push((id.to_string(), object));
+    }
+    out
+}
+
+// the attribute text of every <name ...> tag
+fn tags<'a>(text: &'a str, name: &str) -> Vec<&'a str> {
+    let open = format!("<{}", name);
+    let mut out = Vec::new();
+    for part in text.split(&open).skip(1) {
+        // make sure this isn't a longer tag name like <vertices
+        if !part.starts_with(|c: char| c.is_whitespace() || c == '/' || c == '>') {
+            continue;
+        }
+        out.push(part.split('>').next().unwrap_or(""));
+    }
+    out
+}
+
+fn attr<'a>(tag: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!(" {}=\"", name);
+    let start = tag.find(&key)? + key.len();
+    let len = tag[start..].find('"')?;
+    Some(&tag[start..start + len])
+}
+
+// 3mf transforms are 12 floats, the 3x3 matrix row by row then the translation
+fn parse_transform(s: &str) -> Affine3A {
+    let m: Vec<f32> = s
+        .split_whitespace()
+        .filter_map(|n| n.parse().ok())
+        .collect();
+    if m.len() != 12 {
+        return Affine3A::IDENTITY;
+    }
+    Affine3A::from_cols(
+        Vec3A::new(m[0], m[1], m[2]),
+        Vec3A::new(m[3], m[4], m[5]),
+        Vec3A::new(m[6], m[7], m[8]),
+        Vec3A::new(m[9], m[10], m[11]),
+    )
+}
+
+#[test]
+fn ascii_stl_clip() {
+    let stl = "solid t
+    facet normal 0 0 1
+      outer loop
+        vertex 0 0 0
+        vertex 10 0 10
+        vertex 0 10 10
+      endloop
+    endfacet
+    endsolid t";
+    let mesh = parse_stl(stl.as_bytes()).expect("failed to parse stl");
+    assert_eq!(mesh.triangles.len(), 1);
+    let clipped = mesh.clip_z(Vec3::ZERO, 2.0, 5.0);
+    assert!(!clipped.is_empty());
+    for tri in clipped {
+        for p in tri {
+            assert!(p.z >= 2.0 - 1e-4 && p.z <= 5.0 + 1e-4);
+        }
+    }
+}
+
+#[test]
+fn model_3mf_components() {
+    let root = r#"<model><resources>
+    <object id="1" type="model"><mesh><vertices>
+    <vertex x="0" y="0" z="0"/><vertex x="1" y="0" z="0"/><vertex x="0" y="1" z="0"/>
+    </vertices><triangles><triangle v1="0" v2="1" v3="2"/></triangles></mesh></object>
+    <object id="2" type="model"><components>
+    <component objectid="1" transform="1 0 0 0 1 0 0 0 1 5 5 0"/>
+    </components></object>
+    </resources><build><item objectid="2" transform="1 0 0 0 1 0 0 0 1 0 0 2"/></build></model>"#;
+    let models = HashMap::from([("/3D/3dmodel.model".to_string(), root.to_string())]);
+    let mesh = parse_3mf_models(&models).expect("failed to parse 3mf");
+    assert_eq!(mesh.triangles.len(), 1);
+    assert_eq!(mesh.triangles[0][1], Vec3::new(6.0, 5.0, 2.0));
+}
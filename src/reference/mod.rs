@@ -0,0 +1,130 @@
+mod mesh_reader;
+
+use super::{render::PrintBounds, UiResource};
+use bevy::prelude::*;
+use bevy::render::{mesh::PrimitiveTopology, render_asset::RenderAssetUsages};
+use bevy_mod_picking::prelude::Pickable;
+use mesh_reader::RefMesh;
+
+// source model loaded next to the toolpath to check slicing accuracy
+#[derive(Resource)]
+pub struct ReferenceModel {
+    mesh: RefMesh,
+    pub offset: Vec3,
+    pub alpha: f32,
+    pub visible: bool,
+    // (offset, z_max, z_min) the current mesh was built with
+    built: Option<(Vec3, f32, f32)>,
+    material: Option<Handle<StandardMaterial>>,
+}
+
+impl ReferenceModel {
+    pub fn align(&mut self, bounds: &PrintBounds) {
+        if !bounds.min.is_finite() || !bounds.max.is_finite() {
+            return; // nothing extruded to line up with
+        }
+        self.offset = self.mesh.align_to(bounds.min, bounds.max);
+    }
+}
+
+#[derive(Resource)]
+pub struct LoadReference(pub String);
+
+#[derive(Component)]
+pub struct ReferenceTag;
+
+pub fn load_reference(
+    mut commands: Commands,
+    path: Res<LoadReference>,
+    bounds: Res<PrintBounds>,
+    query: Query<Entity, With<ReferenceTag>>,
+) {
+    commands.remove_resource::<LoadReference>();
+    let mesh = match RefMesh::read(&path.0) {
+        Ok(mesh) => mesh,
+        Err(e) => {
+            println!("failed to load reference model: {}", e);
+            return;
+        }
+    };
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    let mut model = ReferenceModel {
+        mesh,
+        offset: Vec3::ZERO,
+        alpha: 0.4,
+        visible: true,
+        built: None,
+        material: None,
+    };
+    model.align(&bounds);
+    commands.insert_resource(model);
+}
+
+pub fn update_reference(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut model: ResMut<ReferenceModel>,
+    ui_res: Res<UiResource>,
+    mut query: Query<(Entity, &mut Visibility), With<ReferenceTag>>,
+) {
+    if let Some(material) = model.material.as_ref().and_then(|h| materials.get_mut(h)) {
+        material.base_color.set_a(model.alpha);
+    }
+    for (_, mut vis) in query.iter_mut() {
+        *vis = if model.visible {
+            Visibility::Visible
+        } else {
+            Visibility::Hidden
+        };
+    }
+    // only re-clip the mesh when the offset or the z display range moves
+    let key = (model.offset, ui_res.display_z_max.0, ui_res.display_z_min);
+    if model.built == Some(key) {
+        return;
+    }
+    for (entity, _) in query.iter() {
+        commands.entity(entity).despawn();
+    }
+    let triangles = model.mesh.clip_z(key.0, key.2, key.1);
+    let mut positions = Vec::with_capacity(triangles.len() * 3);
+    let mut normals = Vec::with_capacity(triangles.len() * 3);
+    for [a, b, c] in triangles {
+        let normal = (b - a).cross(c - a).normalize_or_zero().to_array();
+        for p in [a, b, c] {
+            positions.push(p.to_array());
+            normals.push(normal);
+        }
+    }
+    let mesh = Mesh::new(
+        PrimitiveTopology::TriangleList,
+        RenderAssetUsages::default(),
+    )
+    .with_inserted_attribute(Mesh::ATTRIBUTE_POSITION, positions)
+    .with_inserted_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    let material = materials.add(StandardMaterial {
+        base_color: Color::rgba(0.8, 0.8, 0.8, model.alpha),
+        alpha_mode: AlphaMode::Blend,
+        double_sided: true,
+        cull_mode: None,
+        ..Default::default()
+    });
+    commands.spawn((
+        PbrBundle {
+            mesh: meshes.add(mesh),
+            material: material.clone(),
+            visibility: if model.visible {
+                Visibility::Visible
+            } else {
+                Visibility::Hidden
+            },
+            ..Default::default()
+        },
+        Pickable::IGNORE,
+        ReferenceTag,
+    ));
+    model.material = Some(material);
+    model.built = Some(key);
+}
@@ -1,51 +1,68 @@
 use super::{
-    print_analyzer::Label, settings::*, ForceRefresh, GCode, IdMap, PickableBundle, Tag, UiResource,
+    print_analyzer::{machine::BedShape, overhang::Support, overhang::ANGLES, Id, Label},
+    settings::*,
+    ColorMode, ForceRefresh, GCode, IdMap, PickableBundle, Reports, Tag, UiResource,
 };
 use bevy::prelude::*;
+use std::collections::HashSet;
+
+// extents of the extrusion moves, used to line up anything drawn next to the toolpath
+#[derive(Resource)]
+pub struct PrintBounds {
+    pub min: Vec3,
+    pub max: Vec3,
+}
 
 pub fn setup_render(
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
     gcode: Res<GCode>,
+    settings: Res<Settings>,
 ) {
-    let (mut x_min, mut y_min, mut z_min): (f32, f32, f32) = (0.0, 0.0, 0.0);
-    let (mut x_max, mut y_max, mut z_max): (f32, f32, f32) = (500.0, 500.0, 500.0);
+    let mut bounds = PrintBounds {
+        min: Vec3::splat(f32::INFINITY),
+        max: Vec3::splat(f32::NEG_INFINITY),
+    };
     for v in gcode.0.vertices.values() {
         if !v.extrusion_move() {
             continue;
         }
-        x_min = x_min.min(v.to.x);
-        y_min = y_min.min(v.to.y);
-        z_min = z_min.min(v.to.z);
-        x_max = x_max.max(v.to.x);
-        y_max = y_max.max(v.to.y);
-        z_max = z_max.max(v.to.z);
+        let pos = Vec3::new(v.to.x, v.to.y, v.to.z);
+        bounds.min = bounds.min.min(pos);
+        bounds.max = bounds.max.max(pos);
     }
-    let origin = Vec3 {
-        x: x_min,
-        y: y_min,
-        z: z_min,
+    // draw the bed from the machine profile
+    let (mesh, translation, rotation) = match settings.machine.bed {
+        BedShape::Rectangle { min, max } => (
+            meshes.add(Cuboid::new(max[0] - min[0], max[1] - min[1], 0.0)),
+            Vec3::new((min[0] + max[0]) / 2.0, (min[1] + max[1]) / 2.0, 0.0),
+            Quat::IDENTITY,
+        ),
+        BedShape::Circle { center, radius } => (
+            meshes.add(Cylinder {
+                radius,
+                half_height: 0.0,
+            }),
+            Vec3::new(center[0], center[1], 0.0),
+            // cylinders stand along y, lay it flat in the xy plane
+            Quat::from_rotation_x(std::f32::consts::FRAC_PI_2),
+        ),
     };
-    let pt1 = Vec3::new(x_max, y_min, z_min);
-    let pt2 = Vec3::new(x_min, y_max, z_min);
-    let max_pt = Vec3::new(x_max, y_max, z_min);
-    let w = x_max - x_min;
-    let l = y_max - y_min;
-    let h = z_max - z_min;
-    // FIXME: make these lines
     let _ = commands.spawn(PbrBundle {
-        mesh: meshes.add(Cuboid::from_corners(origin, max_pt)),
+        mesh,
         material: materials.add(StandardMaterial {
             base_color: Color::GRAY,
             ..Default::default()
         }),
         transform: Transform {
-            translation: Vec3::new(w / 2.0, l / 2.0, 0.0),
+            translation,
+            rotation,
             ..Default::default()
         },
         ..Default::default()
     });
+    commands.insert_resource(bounds);
 }
 
 pub fn render(
@@ -56,16 +73,19 @@ pub fn render(
     gcode: Res<GCode>,
     shapes: Query<Entity, With<Tag>>,
     settings: Res<Settings>,
+    reports: Res<Reports>,
+    ui_res: Res<UiResource>,
 ) {
     for shape in shapes.iter() {
         commands.entity(shape).despawn();
     }
     let gcode = &gcode.0;
+    let beads = &reports.beads;
     let mut pos_list = Vec::new();
     for v in gcode.vertices.values() {
         let (xf, yf, zf) = (v.to.x, v.to.y, v.to.z);
         let (xi, yi, zi) = {
-            if let Some(prev) = v.prev {
+            if let Some(prev) = gcode.lines.prev(&v.id) {
                 let p = gcode.vertices.get(&prev).unwrap();
                 (p.to.x, p.to.y, p.to.z)
             } else {
@@ -73,16 +93,16 @@ pub fn render(
             }
         };
         let (start, end) = (Vec3::new(xi, yi, zi), Vec3::new(xf, yf, zf));
-        let dist = start.distance(end);
-        let flow = v.to.e / dist;
-        pos_list.push((v.id, start, end, flow, v.label));
+        // moves that don't lay down a bead get drawn as thin as travels
+        let radius = beads.get(&v.id).map_or(0.1, |b| b.width / 2.0);
+        pos_list.push((v.id, start, end, radius, v.label));
     }
-    for (id, start, end, flow, label) in pos_list {
+    let collided: HashSet<Id> = reports.collisions.iter().map(|c| c.id).collect();
+    for (id, start, end, radius, label) in pos_list {
         if label == Label::FeedrateChangeOnly || label == Label::Home || label == Label::MysteryMove
         {
             continue;
         }
-        let radius = (flow / std::f32::consts::PI).sqrt();
         let length = start.distance(end);
         let direction = end - start;
         let mut sphere = false;
@@ -108,27 +128,45 @@ pub fn render(
         if label == Label::DeRetraction || label == Label::Retraction {
             sphere = true;
         }
-        let material_handle = match label {
-            Label::PlanarExtrustion | Label::NonPlanarExtrusion | Label::PrePrintMove => materials
-                .add(StandardMaterial {
-                    base_color: settings.extrusion_color,
+        let heat = match ui_res.color_mode {
+            ColorMode::FlowLimit => reports.flow.ratio.get(&id).map(|r| heat_color(*r)),
+            ColorMode::TravelCollisions => collided.contains(&id).then_some(Color::FUCHSIA),
+            ColorMode::Overhang => reports.overhangs.get(&id).map(|o| match o.class {
+                Support::Supported => heat_color(0.0),
+                Support::Overhang(bucket) => heat_color((bucket + 1) as f32 / ANGLES.len() as f32),
+                Support::Bridge => Color::BLUE,
+            }),
+            ColorMode::Label => None,
+        };
+        let material_handle = if let Some(color) = heat {
+            materials.add(StandardMaterial {
+                base_color: color,
+                ..Default::default()
+            })
+        } else {
+            match label {
+                Label::PlanarExtrustion | Label::NonPlanarExtrusion | Label::PrePrintMove => {
+                    materials.add(StandardMaterial {
+                        base_color: settings.extrusion_color,
+                        ..Default::default()
+                    })
+                }
+                Label::TravelMove | Label::LiftZ | Label::LowerZ | Label::Wipe => {
+                    materials.add(StandardMaterial {
+                        base_color: settings.travel_color,
+                        ..Default::default()
+                    })
+                }
+                Label::DeRetraction => materials.add(StandardMaterial {
+                    base_color: settings.deretraction_color,
                     ..Default::default()
                 }),
-            Label::TravelMove | Label::LiftZ | Label::LowerZ | Label::Wipe => {
-                materials.add(StandardMaterial {
-                    base_color: settings.travel_color,
+                Label::Retraction => materials.add(StandardMaterial {
+                    base_color: settings.retraction_color,
                     ..Default::default()
-                })
+                }),
+                _ => panic!(),
             }
-            Label::DeRetraction => materials.add(StandardMaterial {
-                base_color: settings.deretraction_color,
-                ..Default::default()
-            }),
-            Label::Retraction => materials.add(StandardMaterial {
-                base_color: settings.retraction_color,
-                ..Default::default()
-            }),
-            _ => panic!(),
         };
 
         // Calculate the middle point and orientation of the cylinder
@@ -156,9 +194,41 @@ pub fn render(
             .id();
         map.0.insert(id, e_id);
     }
+    // the seam column, tagged with the loop's first move so it shows and hides with it
+    if ui_res.vis_select.seams {
+        let mesh = meshes.add(Sphere { radius: 0.3 });
+        let material = materials.add(StandardMaterial {
+            base_color: Color::WHITE,
+            ..Default::default()
+        });
+        for seam in &reports.seams {
+            commands.spawn((
+                PbrBundle {
+                    mesh: mesh.clone(),
+                    material: material.clone(),
+                    transform: Transform::from_translation(seam.point),
+                    ..Default::default()
+                },
+                Tag { id: seam.id },
+            ));
+        }
+    }
     commands.remove_resource::<ForceRefresh>();
 }
 
+// green at no load through yellow to red at the limit, magenta past it
+fn heat_color(ratio: f32) -> Color {
+    if ratio > 1.0 {
+        return Color::FUCHSIA;
+    }
+    let r = ratio.clamp(0.0, 1.0);
+    if r < 0.5 {
+        Color::rgb(r * 2.0, 1.0, 0.0)
+    } else {
+        Color::rgb(1.0, (1.0 - r) * 2.0, 0.0)
+    }
+}
+
 pub fn update_visibilities(
     mut entity_query: Query<(&Tag, &mut Visibility)>,
     ui_res: Res<UiResource>,
@@ -178,8 +248,9 @@ pub fn update_visibilities(
             };
             if count > v.count
                 && selected
-                && v.to.z < ui_res.display_z_max.0
-                && v.to.z > ui_res.display_z_min
+                // slider values sit exactly on layer heights, so the limits are inclusive
+                && v.to.z <= ui_res.display_z_max.0 + 1e-4
+                && v.to.z >= ui_res.display_z_min - 1e-4
             {
                 *vis = Visibility::Visible;
             } else {
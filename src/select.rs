@@ -31,7 +31,7 @@ pub fn update_selections(
                     }
                 }
             } else if select_type == Choice::Layer {
-                for id in gcode.0.get_same_z(&id.id) {
+                for id in gcode.0.get_layer(&id.id) {
                     let Some(entity) = map.0.get(&id) else {
                         continue;
                     };
@@ -54,7 +54,7 @@ pub fn update_selections(
                     deselect_me.is_selected = false;
                 }
             } else if select_type == Choice::Layer {
-                for id in gcode.0.get_same_z(&id.id) {
+                for id in gcode.0.get_layer(&id.id) {
                     let Some(entity) = map.0.get(&id) else {
                         continue;
                     };
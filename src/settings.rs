@@ -1,3 +1,5 @@
+use crate::print_analyzer::machine::MachineProfile;
+use crate::print_analyzer::usage::Filament;
 use bevy::prelude::{Color, KeyCode, MouseButton, Resource};
 use serde_json::{from_str, Value};
 use std::fs::{read_to_string, File};
@@ -13,6 +15,14 @@ pub struct Settings {
     pub retraction_color: Color,
     pub deretraction_color: Color,
     pub travel_color: Color,
+    pub filament: Filament,
+    // mm^3/s
+    pub max_volumetric_flow: f32,
+    // mm a travel has to clear printed beads by
+    pub travel_clearance: f32,
+    pub machine: MachineProfile,
+    // lint rule, threshold and its value
+    pub lint: Vec<(String, String, f32)>,
 }
 
 fn read_key(settings: &Value, key: &str) -> KeyCode {
@@ -42,12 +52,69 @@ fn read_color(settings: &Value, key: &str) -> Color {
     Color::hex(color).unwrap()
 }
 
-pub fn read_settings() -> Settings {
-    let path = std::env::current_exe()
+// settings files written before a section existed fall back to the defaults
+fn read_f32(settings: &Value, section: &str, key: &str, default: f32) -> f32 {
+    settings
+        .get(section)
+        .and_then(|s| s.get(key))
+        .and_then(|v| v.as_f64())
+        .map_or(default, |v| v as f32)
+}
+
+fn read_filament(settings: &Value) -> Filament {
+    let default = Filament::default();
+    Filament {
+        diameter: read_f32(settings, "filament", "diameter", default.diameter),
+        density: read_f32(settings, "filament", "density", default.density),
+        price: read_f32(settings, "filament", "price per kg", default.price),
+    }
+}
+
+// {"rule": {"param": value}}, applied over the linter's defaults
+fn read_lint(settings: &Value) -> Vec<(String, String, f32)> {
+    let Some(rules) = settings.get("lint").and_then(|l| l.as_object()) else {
+        return Vec::new();
+    };
+    let mut out = Vec::new();
+    for (rule, params) in rules {
+        for (param, value) in params.as_object().into_iter().flatten() {
+            if let Some(value) = value.as_f64() {
+                out.push((rule.clone(), param.clone(), value as f32));
+            }
+        }
+    }
+    out
+}
+
+fn config_path(name: &str) -> std::path::PathBuf {
+    std::env::current_exe()
         .expect("could not find excecutable directory")
         .parent()
         .unwrap()
-        .join(std::path::PathBuf::from("settings.json"));
+        .join(std::path::PathBuf::from(name))
+}
+
+// machine.json lives next to settings.json
+fn read_machine_profile() -> MachineProfile {
+    let path = config_path("machine.json");
+    if !path.exists() {
+        let written = File::create(&path).and_then(|mut f| f.write_all(DEFAULT_MACHINE.as_bytes()));
+        if let Err(e) = written {
+            println!("could not write default machine profile: {}", e);
+        }
+        return MachineProfile::from_json(DEFAULT_MACHINE).unwrap();
+    }
+    let profile = read_to_string(&path)
+        .map_err(|e| e.into())
+        .and_then(|json| MachineProfile::from_json(&json));
+    profile.unwrap_or_else(|e| {
+        println!("invalid machine profile, using defaults: {}", e);
+        MachineProfile::default()
+    })
+}
+
+pub fn read_settings() -> Settings {
+    let path = config_path("settings.json");
     let settings = {
         if path.exists() {
             &read_to_string(&path).unwrap()
@@ -67,6 +134,11 @@ pub fn read_settings() -> Settings {
         retraction_color: read_color(&settings, "retraction color"),
         deretraction_color: read_color(&settings, "deretraction color"),
         travel_color: read_color(&settings, "travel move color"),
+        filament: read_filament(&settings),
+        max_volumetric_flow: read_f32(&settings, "hotend", "max volumetric flow", 15.0),
+        travel_clearance: read_f32(&settings, "analysis", "travel clearance", 0.1),
+        machine: read_machine_profile(),
+        lint: read_lint(&settings),
     }
 }
 
@@ -84,6 +156,42 @@ const DEFAULT_SETTINGS: &str = r#"{
     "buttons" : {
         "mouse orbit": "right",
         "mouse pan": "left" 
+    },
+    "filament" : {
+        "diameter": 1.75,
+        "density": 1.24,
+        "price per kg": 20.0
+    },
+    "hotend" : {
+        "max volumetric flow": 15.0
+    },
+    "analysis" : {
+        "travel clearance": 0.1
+    },
+    "lint" : {
+        "negative cumulative e": { "tolerance": 0.01 },
+        "unmatched retraction": { "tolerance": 0.01 },
+        "abnormal e per mm": { "factor": 3.0 }
+    }
+}"#;
+
+const DEFAULT_MACHINE: &str = r#"{
+    "name": "generic",
+    "bed": {
+        "shape": "rectangle",
+        "size": [250, 210],
+        "origin": [0, 0]
+    },
+    "max z": 220,
+    "nozzle diameter": 0.4,
+    "excluded zones": [],
+    "limits": {
+        "max feedrate": { "x": 500, "y": 500, "z": 12, "e": 120 },
+        "max accel": { "x": 3000, "y": 3000, "z": 100, "e": 10000 },
+        "print accel": 1500,
+        "retract accel": 1500,
+        "travel accel": 3000,
+        "jerk": { "x": 10, "y": 10, "z": 0.3, "e": 5 }
     }
 }"#;
 
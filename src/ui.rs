@@ -1,9 +1,18 @@
 use super::diff::{SelectionLog, SetSelections};
 use super::{
-    FilePath, HoleDelete, MergeDelete, PickSelection, PickingPluginsSettings, Settings,
-    SubdivideSelection,
+    BakeBedMesh, FilePath, HoleDelete, MergeDelete, OptimizeTravel, PickSelection,
+    PickingPluginsSettings, RelocateSeams, Settings, SubdivideSelection, WarpSelection,
 };
-use crate::print_analyzer::Parsed;
+use crate::print_analyzer::arc::ArcStats;
+use crate::print_analyzer::compensate::Skew;
+use crate::print_analyzer::pause::{Pause, PauseKind};
+use crate::print_analyzer::retract::{Retract, ZHop};
+use crate::print_analyzer::snippet::Feature;
+use crate::print_analyzer::tower::{Bands, Tower, TowerParam};
+use crate::print_analyzer::tune::Adjust;
+use crate::print_analyzer::{Id, Parsed};
+use crate::reference::{LoadReference, ReferenceModel};
+use crate::render::PrintBounds;
 use crate::{ForceRefresh, GCode, Tag};
 use bevy::input::keyboard::Key;
 use bevy::input::mouse::MouseMotion;
@@ -20,6 +29,33 @@ pub enum Choice {
     Layer,
 }
 
+#[derive(PartialEq, Clone, Copy)]
+pub enum ColorMode {
+    Label,
+    FlowLimit,
+    TravelCollisions,
+    Overhang,
+}
+
+// where relocated seams go, on the selected loops or every loop if nothing is selected.
+// nearest and aligned aim at the seam point
+#[derive(PartialEq, Clone, Copy)]
+pub enum SeamChoice {
+    Nearest,
+    Rear,
+    Aligned,
+    Random,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum WarpChoice {
+    Sine,
+    Dome,
+    Ramp,
+    // a csv or png height map stretched over the print
+    Map,
+}
+
 #[derive(PartialEq)]
 enum Cursor {
     Pointer,
@@ -33,7 +69,12 @@ pub struct UiResource {
     pub display_z_min: f32,
     pub vertex_counter: u32,
     pub selection_enum: Choice,
+    pub color_mode: ColorMode,
     subdivide_slider: u32,
+    seam_choice: SeamChoice,
+    seam_point: Vec2,
+    warp: WarpSelection,
+    bed_mesh: BakeBedMesh,
     translation_input: String,
     pub gcode_emit: String,
     pub vis_select: VisibilitySelector,
@@ -41,6 +82,23 @@ pub struct UiResource {
     pub rotate_y: f32,
     pub rotate_z: f32,
     pub scale: f32,
+    pub skew_xy: f32,
+    pub skew_yx: f32,
+    adjust: Adjust,
+    // from the first selected move to the last
+    adjust_ramp: (f32, f32),
+    pause: Pause,
+    // where `pause` parks when parking is on
+    park: (bool, Vec2),
+    tower: Tower,
+    retract: Retract,
+    skew: Skew,
+    // percent
+    shrinkage: Vec2,
+    reference_path: String,
+    // write G2/G3 arcs on export, to within the tolerance in mm
+    arc_fit: (bool, f32),
+    arc_stats: Option<ArcStats>,
     cursor_enum: Cursor,
 }
 
@@ -51,7 +109,23 @@ impl Default for UiResource {
             display_z_min: 0.0,
             vertex_counter: 0,
             selection_enum: Choice::Vertex,
+            color_mode: ColorMode::Label,
             subdivide_slider: 1,
+            seam_choice: SeamChoice::Nearest,
+            seam_point: Vec2::ZERO,
+            warp: WarpSelection {
+                choice: WarpChoice::Sine,
+                amplitude: 0.5,
+                size: 20.0,
+                max_len: 1.0,
+                path: String::new(),
+            },
+            bed_mesh: BakeBedMesh {
+                path: String::new(),
+                layers: 1,
+                fade: 5.0,
+                max_len: 2.0,
+            },
             translation_input: String::new(),
             gcode_emit: String::new(),
             vis_select: VisibilitySelector::default(),
@@ -59,6 +133,39 @@ impl Default for UiResource {
             rotate_y: 0.0,
             rotate_z: 0.0,
             scale: 1.0,
+            skew_xy: 0.0,
+            skew_yx: 0.0,
+            adjust: Adjust::Multiply,
+            adjust_ramp: (1.0, 1.0),
+            pause: Pause {
+                kind: PauseKind::M600,
+                park: None,
+                lift: 5.0,
+                retract: 1.0,
+            },
+            park: (true, Vec2::new(0.0, 200.0)),
+            tower: Tower {
+                param: TowerParam::Temperature,
+                start: 220.0,
+                step: -5.0,
+                bands: Bands::Height(5.0),
+                skip: 0,
+            },
+            retract: Retract {
+                length: 0.8,
+                speed: 2400.0,
+                prime_speed: 1800.0,
+                firmware: false,
+                hop: ZHop::Off,
+                hop_height: 0.4,
+                hop_speed: 600.0,
+                min_travel: 2.0,
+            },
+            skew: Skew::default(),
+            shrinkage: Vec2::ZERO,
+            reference_path: String::new(),
+            arc_fit: (false, 0.05),
+            arc_stats: None,
             cursor_enum: Cursor::Pointer,
         }
     }
@@ -71,6 +178,7 @@ pub struct VisibilitySelector {
     pub deretraction: bool,
     pub travel: bool,
     pub preprint: bool,
+    pub seams: bool,
 }
 impl Default for VisibilitySelector {
     fn default() -> Self {
@@ -81,17 +189,31 @@ impl Default for VisibilitySelector {
             deretraction: false,
             travel: false,
             preprint: false,
+            seams: false,
         }
     }
 }
 
 pub fn ui_setup(gcode: Res<GCode>, mut ui_res: ResMut<UiResource>) {
     for (_, v) in gcode.0.vertices.iter() {
-        ui_res.display_z_max.1 = ui_res.display_z_max.1.max(v.to.z);
         ui_res.vertex_counter = ui_res.vertex_counter.max(v.count);
     }
+    ui_res.display_z_max.1 = gcode.0.layers().last().map_or(0.0, |l| l.z);
     ui_res.display_z_max.0 = ui_res.display_z_max.1;
 }
+
+// z of the layer closest to z, `step` layers up or down from it
+fn snap_to_layer(gcode: &Parsed, z: f32, step: isize) -> f32 {
+    let layers = gcode.layers();
+    let Some(closest) = layers
+        .iter()
+        .min_by(|a, b| (a.z - z).abs().total_cmp(&(b.z - z).abs()))
+    else {
+        return z;
+    };
+    let i = (closest.index as isize + step).clamp(0, layers.len() as isize - 1);
+    layers[i as usize].z
+}
 pub fn toolbar(mut commands: Commands, mut contexts: EguiContexts) {
     let ctx = contexts.ctx_mut();
     egui::TopBottomPanel::top("toolbar").show(ctx, |ui| {
@@ -114,6 +236,7 @@ pub fn export_dialogue(
     mut path: ResMut<FilePath>,
     mut open: ResMut<ExportDialogue>,
     gcode: Res<GCode>,
+    mut ui_res: ResMut<UiResource>,
 ) {
     if let Ok(window) = window.get_single() {
         let x = window.width() / 2.0;
@@ -126,10 +249,27 @@ pub fn export_dialogue(
                 .show(context.get_mut(), |ui| {
                     ui.label("Path:");
                     ui.text_edit_singleline(&mut path.0);
+                    ui.horizontal(|ui| {
+                        let (fit, tolerance) = &mut ui_res.arc_fit;
+                        ui.checkbox(fit, "arcs");
+                        ui.add(egui::DragValue::new(tolerance).speed(0.005).suffix(" mm"))
+                            .on_hover_text("how far arcs may stray from the moves they replace");
+                        if let Some(stats) = ui_res.arc_stats {
+                            ui.label(format!("last {:.1}x fewer moves", stats.ratio()));
+                        }
+                    });
                     if ui.button("Export").clicked() {
                         let path = std::path::PathBuf::from(path.0.clone());
                         if let Some(path) = path.to_str() {
-                            let _ = gcode.0.write_to_file(path);
+                            match ui_res.arc_fit {
+                                (true, tolerance) => {
+                                    let stats = gcode.0.write_arcs_to_file(path, tolerance);
+                                    ui_res.arc_stats = stats.ok();
+                                }
+                                (false, _) => {
+                                    let _ = gcode.0.write_to_file(path);
+                                }
+                            }
                             commands.remove_resource::<ExportDialogue>();
                         }
                     }
@@ -172,6 +312,21 @@ pub fn right_click_menu(mut contexts: EguiContexts, pos: Res<RightClick>) {
         .show(contexts.ctx_mut(), |ui| if ui.button("asdf").clicked() {});
 }
 
+// every line of the selected shapes or layers, or just the selected lines
+fn expand(gcode: &Parsed, selection: &HashSet<Id>, choice: Choice) -> HashSet<Id> {
+    match choice {
+        Choice::Vertex => selection.clone(),
+        Choice::Shape => selection
+            .iter()
+            .flat_map(|id| gcode.get_shape(id))
+            .collect(),
+        Choice::Layer => selection
+            .iter()
+            .flat_map(|id| gcode.get_layer(id))
+            .collect(),
+    }
+}
+
 pub fn ui_system(
     mut contexts: EguiContexts,
     mut commands: Commands,
@@ -180,6 +335,8 @@ pub fn ui_system(
     window: Query<&Window, With<PrimaryWindow>>,
     mut gcode: ResMut<GCode>,
     s_query: Query<(&mut PickSelection, &Tag)>,
+    mut reference: Option<ResMut<ReferenceModel>>,
+    bounds: Res<PrintBounds>,
 ) {
     let window = window.get_single().unwrap();
     let panel_width = window.width() / 6.0;
@@ -203,16 +360,16 @@ pub fn ui_system(
                 ui.add_space(spacing);
                 let mx = ui_res.display_z_max.1;
                 ui.horizontal(|ui| {
-                    ui.add(
-                        egui::Slider::new(&mut ui_res.display_z_max.0, 0.0..=mx)
-                            .vertical()
-                            .step_by(0.1),
-                    );
-                    ui.add(
-                        egui::Slider::new(&mut ui_res.display_z_min, mx..=0.0)
-                            .vertical()
-                            .step_by(0.1),
-                    );
+                    let top =
+                        ui.add(egui::Slider::new(&mut ui_res.display_z_max.0, 0.0..=mx).vertical());
+                    if top.changed() {
+                        ui_res.display_z_max.0 = snap_to_layer(&gcode.0, ui_res.display_z_max.0, 0);
+                    }
+                    let bottom =
+                        ui.add(egui::Slider::new(&mut ui_res.display_z_min, mx..=0.0).vertical());
+                    if bottom.changed() {
+                        ui_res.display_z_min = snap_to_layer(&gcode.0, ui_res.display_z_min, 0);
+                    }
                 });
                 let steps = [
                     (100, "<<<"),
@@ -265,6 +422,83 @@ pub fn ui_system(
                     }
                 });
                 ui.add_space(spacing);
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut ui_res.seam_choice, SeamChoice::Nearest, "Nearest");
+                    ui.radio_value(&mut ui_res.seam_choice, SeamChoice::Rear, "Rear");
+                    ui.radio_value(&mut ui_res.seam_choice, SeamChoice::Aligned, "Aligned");
+                    ui.radio_value(&mut ui_res.seam_choice, SeamChoice::Random, "Random");
+                    if ui.button("Relocate seams").clicked() {
+                        let point = ui_res.seam_point;
+                        commands.insert_resource(RelocateSeams(ui_res.seam_choice, point));
+                    }
+                });
+                if matches!(
+                    ui_res.seam_choice,
+                    SeamChoice::Nearest | SeamChoice::Aligned
+                ) {
+                    ui.horizontal(|ui| {
+                        let point = &mut ui_res.seam_point;
+                        ui.label("seam point");
+                        ui.add(egui::DragValue::new(&mut point.x).speed(1.0).prefix("x"));
+                        ui.add(egui::DragValue::new(&mut point.y).speed(1.0).prefix("y"));
+                    });
+                }
+                ui.add_space(spacing);
+                ui.label("z warp");
+                ui.horizontal(|ui| {
+                    let warp = &mut ui_res.warp;
+                    ui.radio_value(&mut warp.choice, WarpChoice::Sine, "Sine");
+                    ui.radio_value(&mut warp.choice, WarpChoice::Dome, "Dome");
+                    ui.radio_value(&mut warp.choice, WarpChoice::Ramp, "Ramp");
+                    ui.radio_value(&mut warp.choice, WarpChoice::Map, "Map");
+                });
+                ui.horizontal(|ui| {
+                    let warp = &mut ui_res.warp;
+                    ui.add(egui::DragValue::new(&mut warp.amplitude).speed(0.05))
+                        .on_hover_text("height in mm");
+                    let size = egui::DragValue::new(&mut warp.size)
+                        .speed(0.5)
+                        .clamp_range(0.1..=f32::MAX);
+                    ui.add(size)
+                        .on_hover_text("wavelength, radius or ramp length in mm");
+                    let max_len = egui::DragValue::new(&mut warp.max_len)
+                        .speed(0.1)
+                        .clamp_range(0.05..=f32::MAX);
+                    ui.add(max_len)
+                        .on_hover_text("longest move left after subdividing");
+                });
+                if ui_res.warp.choice == WarpChoice::Map {
+                    ui.text_edit_singleline(&mut ui_res.warp.path)
+                        .on_hover_text("path to a .csv in mm or a greyscale .png");
+                }
+                if ui.button("Warp").clicked() && !selection.is_empty() {
+                    commands.insert_resource(ui_res.warp.clone());
+                }
+                ui.add_space(spacing);
+                ui.label("bed mesh");
+                ui.text_edit_singleline(&mut ui_res.bed_mesh.path)
+                    .on_hover_text("klipper [bed_mesh] profile, marlin M420 V output or .csv");
+                ui.horizontal(|ui| {
+                    let mesh = &mut ui_res.bed_mesh;
+                    ui.add(egui::DragValue::new(&mut mesh.layers).clamp_range(1..=1000))
+                        .on_hover_text("layers getting the full offset");
+                    ui.add(egui::DragValue::new(&mut mesh.fade).speed(0.1))
+                        .on_hover_text("mm to fade the offset out over");
+                    ui.add(egui::DragValue::new(&mut mesh.max_len).speed(0.1))
+                        .on_hover_text("longest move left after subdividing");
+                    if ui.button("Bake").clicked() && !mesh.path.is_empty() {
+                        commands.insert_resource(mesh.clone());
+                    }
+                });
+                ui.add_space(spacing);
+                if ui
+                    .button("Optimize travel")
+                    .on_hover_text("reorder the shapes of every layer")
+                    .clicked()
+                {
+                    commands.init_resource::<OptimizeTravel>();
+                }
+                ui.add_space(spacing);
                 ui.horizontal(|ui| {
                     let _ = ui.checkbox(&mut ui_res.vis_select.extrusion, "extrusion");
                     let _ = ui.checkbox(&mut ui_res.vis_select.travel, "travel");
@@ -272,6 +506,25 @@ pub fn ui_system(
                     let _ = ui.checkbox(&mut ui_res.vis_select.wipe, "wipe");
                     let _ = ui.checkbox(&mut ui_res.vis_select.deretraction, "deretraction");
                     let _ = ui.checkbox(&mut ui_res.vis_select.preprint, "preprint");
+                    // seam markers are only spawned when drawing
+                    if ui.checkbox(&mut ui_res.vis_select.seams, "seams").changed() {
+                        commands.init_resource::<ForceRefresh>();
+                    }
+                });
+                ui.add_space(spacing);
+                ui.horizontal(|ui| {
+                    let mode = ui_res.color_mode;
+                    ui.radio_value(&mut ui_res.color_mode, ColorMode::Label, "Move type");
+                    ui.radio_value(&mut ui_res.color_mode, ColorMode::FlowLimit, "Flow limit");
+                    ui.radio_value(
+                        &mut ui_res.color_mode,
+                        ColorMode::TravelCollisions,
+                        "Collisions",
+                    );
+                    ui.radio_value(&mut ui_res.color_mode, ColorMode::Overhang, "Overhang");
+                    if mode != ui_res.color_mode {
+                        commands.init_resource::<ForceRefresh>();
+                    }
                 });
                 ui.add_space(spacing);
                 ui.horizontal(|ui| {
@@ -286,33 +539,17 @@ pub fn ui_system(
                         let x = params.next().unwrap().parse::<f32>().unwrap();
                         let y = params.next().unwrap().parse::<f32>().unwrap();
                         let z = params.next().unwrap().parse::<f32>().unwrap();
-                        match enu {
-                            Choice::Vertex => {
-                                for selection in &selection {
-                                    gcode.0.translate(selection, x, y, z);
-                                }
-                            }
-                            Choice::Shape => {
-                                let mut shapes = HashSet::new();
-                                for selection in &selection {
-                                    let shape = gcode.0.get_shape(selection);
-                                    shapes.extend(&shape);
-                                }
-                                for vertex in shapes.iter() {
-                                    gcode.0.translate(vertex, x, y, z);
-                                }
-                            }
-                            Choice::Layer => {
-                                let mut layers = HashSet::new();
-                                for selection in &selection {
-                                    let layer = gcode.0.get_same_z(selection);
-                                    layers.extend(&layer);
-                                }
-                                for vertex in layers.iter() {
-                                    gcode.0.translate(vertex, x, y, z);
+                        let mut moved = HashSet::new();
+                        for selection in &selection {
+                            match enu {
+                                Choice::Vertex => {
+                                    moved.insert(*selection);
                                 }
+                                Choice::Shape => moved.extend(gcode.0.get_shape(selection)),
+                                Choice::Layer => moved.extend(gcode.0.get_layer(selection)),
                             }
                         }
+                        gcode.0.translate(&moved, Vec3::new(x, y, z));
                         commands.init_resource::<ForceRefresh>();
                     }
                 });
@@ -324,7 +561,33 @@ pub fn ui_system(
                 });
                 ui.add_space(spacing);
                 ui.text_edit_multiline(&mut ui_res.gcode_emit)
-                    .on_hover_text("enter custom gcode");
+                    .on_hover_text("enter custom gcode, {layer} {z} and {tool} are filled in");
+                ui.horizontal(|ui| {
+                    let before = ui.button("Insert before").clicked();
+                    let replace = ui.button("Replace").clicked();
+                    let after = ui.button("Insert after").clicked();
+                    if !(before || replace || after) || selection.is_empty() {
+                        return;
+                    }
+                    let feature = match ui_res.selection_enum {
+                        Choice::Vertex => Feature::Vertex,
+                        Choice::Shape => Feature::Shape,
+                        Choice::Layer => Feature::Layer,
+                    };
+                    let features = gcode.0.features(&selection, feature);
+                    let snippet = &ui_res.gcode_emit;
+                    let result = if before {
+                        gcode.0.insert_before(&features, snippet)
+                    } else if replace {
+                        gcode.0.replace_with(&features, snippet)
+                    } else {
+                        gcode.0.insert_after(&features, snippet)
+                    };
+                    match result {
+                        Ok(()) => commands.init_resource::<ForceRefresh>(),
+                        Err(e) => println!("failed to insert gcode: {}", e),
+                    }
+                });
                 ui.add_space(spacing);
                 ui.horizontal(|ui| {
                     ui.add(egui::Slider::new(&mut ui_res.rotate_x, -180.0..=180.0).vertical());
@@ -332,15 +595,13 @@ pub fn ui_system(
                     ui.add(egui::Slider::new(&mut ui_res.rotate_z, -180.0..=180.0).vertical());
                     if ui.button("Rotate").clicked() {
                         let origin = gcode.0.get_centroid(&selection);
-                        for vertex in &selection {
-                            gcode.0.rotate(
-                                vertex,
-                                origin,
-                                ui_res.rotate_x,
-                                ui_res.rotate_y,
-                                ui_res.rotate_z,
-                            );
-                        }
+                        gcode.0.rotate(
+                            &selection,
+                            origin,
+                            ui_res.rotate_x,
+                            ui_res.rotate_y,
+                            ui_res.rotate_z,
+                        );
                         commands.init_resource::<ForceRefresh>();
                     }
                 });
@@ -349,12 +610,268 @@ pub fn ui_system(
                     ui.add(egui::Slider::new(&mut ui_res.scale, 0.1..=10.0));
                     if ui.button("Scale").clicked() {
                         let origin = gcode.0.get_centroid(&selection);
-                        for vertex in &selection {
-                            gcode.0.scale(vertex, origin, ui_res.scale);
+                        gcode.0.scale(&selection, origin, ui_res.scale);
+                        commands.init_resource::<ForceRefresh>();
+                    }
+                });
+                ui.add_space(spacing);
+                ui.horizontal(|ui| {
+                    for (name, normal) in [("Mirror X", Vec3::X), ("Mirror Y", Vec3::Y)] {
+                        if ui.button(name).clicked() && !selection.is_empty() {
+                            let origin = gcode.0.get_centroid(&selection);
+                            gcode.0.mirror(&selection, origin, normal);
+                            commands.init_resource::<ForceRefresh>();
                         }
+                    }
+                });
+                ui.add_space(spacing);
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::DragValue::new(&mut ui_res.skew_xy)
+                            .speed(0.01)
+                            .prefix("xy "),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut ui_res.skew_yx)
+                            .speed(0.01)
+                            .prefix("yx "),
+                    );
+                    if ui.button("Skew").clicked() && !selection.is_empty() {
+                        let origin = gcode.0.get_centroid(&selection);
+                        gcode
+                            .0
+                            .skew(&selection, origin, ui_res.skew_xy, ui_res.skew_yx);
+                        commands.init_resource::<ForceRefresh>();
+                    }
+                });
+                ui.add_space(spacing);
+                ui.label("flow and feedrate");
+                ui.horizontal(|ui| {
+                    ui.radio_value(&mut ui_res.adjust, Adjust::Multiply, "Multiply");
+                    ui.radio_value(&mut ui_res.adjust, Adjust::Set, "Set")
+                        .on_hover_text("e per mm, or mm/min");
+                    let (start, end) = &mut ui_res.adjust_ramp;
+                    ui.add(egui::DragValue::new(start).speed(0.01))
+                        .on_hover_text("at the start of the selection");
+                    ui.add(egui::DragValue::new(end).speed(0.01))
+                        .on_hover_text("at the end of the selection");
+                });
+                ui.horizontal(|ui| {
+                    let (adjust, (start, end)) = (ui_res.adjust, ui_res.adjust_ramp);
+                    if ui.button("Flow").clicked() && !selection.is_empty() {
+                        let selection = expand(&gcode.0, &selection, ui_res.selection_enum);
+                        gcode.0.adjust_flow(&selection, adjust, start, end);
+                        commands.init_resource::<ForceRefresh>();
+                    }
+                    if ui.button("Feedrate").clicked() && !selection.is_empty() {
+                        let selection = expand(&gcode.0, &selection, ui_res.selection_enum);
+                        gcode.0.adjust_feedrate(&selection, adjust, start, end);
+                        commands.init_resource::<ForceRefresh>();
+                    }
+                });
+                ui.add_space(spacing);
+                ui.label("pause at selected layers");
+                ui.horizontal(|ui| {
+                    let kind = &mut ui_res.pause.kind;
+                    ui.radio_value(kind, PauseKind::M600, "M600");
+                    ui.radio_value(kind, PauseKind::Pause, "PAUSE");
+                    ui.radio_value(kind, PauseKind::M601, "M601");
+                });
+                ui.horizontal(|ui| {
+                    let (parking, park) = &mut ui_res.park;
+                    ui.checkbox(parking, "park at");
+                    ui.add(egui::DragValue::new(&mut park.x).speed(1.0).prefix("x"));
+                    ui.add(egui::DragValue::new(&mut park.y).speed(1.0).prefix("y"));
+                });
+                ui.horizontal(|ui| {
+                    let pause = &mut ui_res.pause;
+                    ui.add(
+                        egui::DragValue::new(&mut pause.lift)
+                            .speed(0.1)
+                            .prefix("lift "),
+                    )
+                    .on_hover_text("mm to raise z by while paused");
+                    ui.add(
+                        egui::DragValue::new(&mut pause.retract)
+                            .speed(0.1)
+                            .prefix("retract "),
+                    )
+                    .on_hover_text("mm of filament pulled back while paused");
+                    if ui.button("Pause").clicked() && !selection.is_empty() {
+                        let (parking, park) = ui_res.park;
+                        let pause = Pause {
+                            park: parking.then_some(park),
+                            ..ui_res.pause.clone()
+                        };
+                        let layers = gcode.0.features(&selection, Feature::Layer);
+                        match gcode.0.insert_pauses(&layers, &pause) {
+                            Ok(()) => commands.init_resource::<ForceRefresh>(),
+                            Err(e) => println!("failed to insert pause: {}", e),
+                        }
+                    }
+                });
+                ui.add_space(spacing);
+                ui.label("retraction");
+                ui.horizontal(|ui| {
+                    let retract = &mut ui_res.retract;
+                    ui.add(
+                        egui::DragValue::new(&mut retract.length)
+                            .speed(0.05)
+                            .suffix(" mm"),
+                    );
+                    ui.add(egui::DragValue::new(&mut retract.speed).speed(10.0))
+                        .on_hover_text("retraction speed in mm/min");
+                    ui.add(egui::DragValue::new(&mut retract.prime_speed).speed(10.0))
+                        .on_hover_text("deretraction speed in mm/min");
+                    ui.checkbox(&mut retract.firmware, "G10");
+                });
+                ui.horizontal(|ui| {
+                    let hop = &mut ui_res.retract.hop;
+                    ui.radio_value(hop, ZHop::Off, "No hop");
+                    ui.radio_value(hop, ZHop::Straight, "Straight");
+                    ui.radio_value(hop, ZHop::Ramp, "Ramp");
+                    ui.radio_value(hop, ZHop::Spiral, "Spiral");
+                });
+                ui.horizontal(|ui| {
+                    let retract = &mut ui_res.retract;
+                    ui.add(egui::DragValue::new(&mut retract.hop_height).speed(0.05))
+                        .on_hover_text("z hop in mm");
+                    ui.add(egui::DragValue::new(&mut retract.hop_speed).speed(10.0))
+                        .on_hover_text("z hop speed in mm/min");
+                    ui.add(egui::DragValue::new(&mut retract.min_travel).speed(0.1))
+                        .on_hover_text("shortest travel retracted when nothing is selected");
+                    if ui.button("Retract").clicked() {
+                        gcode.0.insert_retractions(&selection, retract);
                         commands.init_resource::<ForceRefresh>();
                     }
                 });
+                ui.add_space(spacing);
+                ui.label("calibration tower");
+                ui.horizontal_wrapped(|ui| {
+                    let param = &mut ui_res.tower.param;
+                    ui.radio_value(param, TowerParam::Temperature, "Temperature");
+                    ui.radio_value(param, TowerParam::Speed, "Speed");
+                    ui.radio_value(param, TowerParam::Flow, "Flow");
+                    ui.radio_value(
+                        param,
+                        TowerParam::PressureAdvance { klipper: false },
+                        "M900",
+                    );
+                    ui.radio_value(
+                        param,
+                        TowerParam::PressureAdvance { klipper: true },
+                        "SET_PA",
+                    );
+                    ui.radio_value(param, TowerParam::Retraction, "Retraction");
+                });
+                ui.horizontal(|ui| {
+                    let tower = &mut ui_res.tower;
+                    ui.add(
+                        egui::DragValue::new(&mut tower.start)
+                            .speed(0.01)
+                            .prefix("start "),
+                    )
+                    .on_hover_text("value of the first band, a multiplier for speed and flow");
+                    ui.add(
+                        egui::DragValue::new(&mut tower.step)
+                            .speed(0.01)
+                            .prefix("step "),
+                    )
+                    .on_hover_text("added for every band after it");
+                });
+                ui.horizontal(|ui| {
+                    let tower = &mut ui_res.tower;
+                    match &mut tower.bands {
+                        Bands::Height(h) => {
+                            ui.add(egui::DragValue::new(h).speed(0.1).suffix(" mm"));
+                        }
+                        Bands::Layers(n) => {
+                            ui.add(
+                                egui::DragValue::new(n)
+                                    .clamp_range(1..=1000)
+                                    .suffix(" layers"),
+                            );
+                        }
+                    }
+                    if ui.button("mm / layers").clicked() {
+                        tower.bands = match tower.bands {
+                            Bands::Height(_) => Bands::Layers(10),
+                            Bands::Layers(_) => Bands::Height(5.0),
+                        };
+                    }
+                    ui.add(egui::DragValue::new(&mut tower.skip).prefix("skip "))
+                        .on_hover_text("layers at the bottom left alone");
+                    if ui.button("Tower").clicked() {
+                        match gcode.0.calibration_tower(tower) {
+                            Ok(_) => commands.init_resource::<ForceRefresh>(),
+                            Err(e) => println!("failed to build tower: {}", e),
+                        }
+                    }
+                });
+                ui.add_space(spacing);
+                ui.label("skew and shrinkage");
+                ui.horizontal(|ui| {
+                    let skew = &mut ui_res.skew;
+                    for (value, axes) in [
+                        (&mut skew.xy, "xy"),
+                        (&mut skew.xz, "xz"),
+                        (&mut skew.yz, "yz"),
+                    ] {
+                        ui.add(egui::DragValue::new(value).speed(0.0005).prefix(axes))
+                            .on_hover_text("SET_SKEW or M852 factor");
+                    }
+                });
+                ui.horizontal(|ui| {
+                    let shrinkage = &mut ui_res.shrinkage;
+                    ui.add(
+                        egui::DragValue::new(&mut shrinkage.x)
+                            .speed(0.05)
+                            .suffix("%"),
+                    )
+                    .on_hover_text("how much smaller than the model prints come out in x");
+                    ui.add(
+                        egui::DragValue::new(&mut shrinkage.y)
+                            .speed(0.05)
+                            .suffix("%"),
+                    )
+                    .on_hover_text("how much smaller than the model prints come out in y");
+                    if ui
+                        .button("Compensate")
+                        .on_hover_text("the selection, or the whole print")
+                        .clicked()
+                    {
+                        let origin = ((bounds.min + bounds.max) / 2.0).truncate().extend(0.0);
+                        let shrinkage = ui_res.shrinkage / 100.0;
+                        gcode
+                            .0
+                            .compensate(&selection, origin, ui_res.skew, shrinkage);
+                        commands.init_resource::<ForceRefresh>();
+                    }
+                });
+                ui.add_space(spacing);
+                ui.label("reference model");
+                ui.horizontal(|ui| {
+                    ui.text_edit_singleline(&mut ui_res.reference_path)
+                        .on_hover_text("path to .stl or .3mf");
+                    if ui.button("Load").clicked() && !ui_res.reference_path.is_empty() {
+                        commands.insert_resource(LoadReference(ui_res.reference_path.clone()));
+                    }
+                });
+                if let Some(reference) = reference.as_mut() {
+                    ui.horizontal(|ui| {
+                        ui.checkbox(&mut reference.visible, "show");
+                        ui.add(egui::Slider::new(&mut reference.alpha, 0.0..=1.0).text("opacity"));
+                    });
+                    ui.horizontal(|ui| {
+                        ui.add(egui::DragValue::new(&mut reference.offset.x).speed(0.1));
+                        ui.add(egui::DragValue::new(&mut reference.offset.y).speed(0.1));
+                        ui.add(egui::DragValue::new(&mut reference.offset.z).speed(0.1));
+                        if ui.button("Auto align").clicked() {
+                            reference.align(&bounds);
+                        }
+                    });
+                }
+                ui.add_space(spacing);
                 if ui.button("Save").clicked() {
                     let _ = gcode.0.write_to_file("./test_output.gcode");
                 }
@@ -370,13 +887,14 @@ pub struct VertexCounter {
 impl VertexCounter {
     pub fn build(gcode: &Parsed) -> VertexCounter {
         VertexCounter {
-            max: gcode.vertices.keys().len() as u32,
+            max: gcode.vertices.len() as u32,
         }
     }
 }
 
 pub fn key_system(
     mut commands: Commands,
+    gcode: Res<GCode>,
     mut ui_res: ResMut<UiResource>,
     mut keys: ResMut<ButtonInput<KeyCode>>,
     mut log: ResMut<SelectionLog>,
@@ -390,9 +908,9 @@ pub fn key_system(
     } else if keys.pressed(KeyCode::ArrowRight) {
         ui_res.vertex_counter += 1;
     } else if keys.pressed(KeyCode::ArrowUp) {
-        ui_res.display_z_max.0 += 0.2;
+        ui_res.display_z_max.0 = snap_to_layer(&gcode.0, ui_res.display_z_max.0, 1);
     } else if keys.pressed(KeyCode::ArrowDown) {
-        ui_res.display_z_max.0 -= 0.2;
+        ui_res.display_z_max.0 = snap_to_layer(&gcode.0, ui_res.display_z_max.0, -1);
     } 
     // check for ctrl press, and then check if shift also held
     else if keys.any_pressed([KeyCode::ControlRight, KeyCode::ControlLeft]) {